@@ -0,0 +1,91 @@
+use std::io::{self, Read};
+
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use snafu::{ResultExt, Snafu};
+use warp::http::StatusCode;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("The body is larger than {} bytes when decompressed", limit))]
+    TooLarge { limit: u64 },
+    #[snafu(display("{} is not a supported content encoding", encoding))]
+    UnsupportedEncoding { encoding: String },
+    #[snafu(display("Could not decompress the body: {}", source))]
+    Decompress { source: io::Error },
+    #[snafu(display("Invalid JSON: {}", source))]
+    Json { source: serde_json::Error },
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnsupportedEncoding { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Decompress { .. } | Error::Json { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Parses a JSON body that may be gzipped, as given by its `Content-Encoding`. A gzipped body
+/// is never decompressed past `limit` bytes, so a small body can't expand into a huge one.
+pub fn decode_json<T: DeserializeOwned>(
+    body: &[u8],
+    content_encoding: Option<&str>,
+    limit: u64,
+) -> Result<T, Error> {
+    let json = match content_encoding.map(str::trim) {
+        None | Some("identity") => body.to_vec(),
+        Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+            let mut json = vec![];
+            // Reading one byte past the limit tells a body that fits exactly from a larger one
+            GzDecoder::new(body)
+                .take(limit + 1)
+                .read_to_end(&mut json)
+                .context(Decompress)?;
+            if json.len() as u64 > limit {
+                return Err(Error::TooLarge { limit });
+            }
+            json
+        }
+        Some(encoding) => {
+            return Err(Error::UnsupportedEncoding {
+                encoding: encoding.to_owned(),
+            })
+        }
+    };
+    serde_json::from_slice(&json).context(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn caps_the_decompressed_size() {
+        let json = format!("[{}0]", "0,".repeat(1000));
+        let body = gzip(json.as_bytes());
+        assert!(body.len() < 100);
+
+        let limit = json.len() as u64;
+        let numbers: Vec<u8> = decode_json(&body, Some("gzip"), limit).unwrap();
+        assert_eq!(numbers.len(), 1001);
+
+        match decode_json::<Vec<u8>>(&body, Some("gzip"), limit - 1) {
+            Err(Error::TooLarge { .. }) => {}
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+        assert!(decode_json::<Vec<u8>>(&body, Some("br"), limit).is_err());
+        assert!(decode_json::<Vec<u8>>(json.as_bytes(), None, 0).is_ok());
+    }
+}
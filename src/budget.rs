@@ -0,0 +1,50 @@
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long a request may take before it is abandoned, counted from when it arrived
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    /// A budget of `millis` milliseconds from now, where 0 is no limit
+    pub fn start(millis: u64) -> Self {
+        Budget {
+            deadline: Some(millis)
+                .filter(|&millis| millis > 0)
+                .map(|millis| Instant::now() + Duration::from_millis(millis)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Budget { deadline: None }
+    }
+
+    /// Time left before the deadline, or `None` if there is none
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Fails once the deadline has passed, so the work done so far can be dropped
+    pub fn check(&self) -> Result<(), Exceeded> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Exceeded),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The time budget of a request ran out before it was done
+#[derive(Debug, Clone, Copy)]
+pub struct Exceeded;
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The request took longer than its time budget")
+    }
+}
+
+impl Error for Exceeded {}
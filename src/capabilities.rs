@@ -0,0 +1,137 @@
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::logo;
+
+/// What a deployment supports, so clients can adapt to it
+#[derive(Serialize)]
+pub struct Capabilities {
+    formats: &'static [&'static str],
+    options: Vec<LogoOption>,
+    limits: Limits,
+    features: Features,
+}
+
+/// An option of the logo routes
+#[derive(Serialize)]
+struct LogoOption {
+    name: &'static str,
+    #[serde(rename = "type")]
+    kind: OptionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    minimum: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maximum: Option<u32>,
+    /// The values it can have, for the options that can only have a few
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    values: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum OptionType {
+    Integer,
+    Boolean,
+    String,
+    /// `rrggbb` or `rrggbbaa` in hex
+    Color,
+}
+
+#[derive(Serialize)]
+struct Limits {
+    /// Where 0 is no limit
+    max_history_rows: u32,
+    max_image_size: u32,
+    max_body_size: u64,
+    max_shadow_blur: u32,
+}
+
+#[derive(Serialize)]
+struct Features {
+    websocket: bool,
+    history: bool,
+    thumbnails: bool,
+    mjpeg: bool,
+    transitions: bool,
+}
+
+const FORMATS: &[&str] = &["png", "ico", "jpeg"];
+
+/// The options of `logo::LogoOptions`, with the limits that don't depend on the config
+const LOGO_OPTIONS: &[(&str, OptionType, Option<u32>, Option<u32>)] = &[
+    ("size", OptionType::Integer, Some(1), None),
+    (
+        "character",
+        OptionType::Integer,
+        Some(0),
+        Some(logo::CHARACTERS as u32 - 1),
+    ),
+    ("crop", OptionType::Boolean, None, None),
+    ("pad", OptionType::Integer, Some(0), None),
+    ("trim", OptionType::Boolean, None, None),
+    ("padding", OptionType::Integer, Some(0), None),
+    ("smooth", OptionType::Boolean, None, None),
+    ("format", OptionType::String, None, None),
+    ("preset", OptionType::String, None, None),
+    ("tint", OptionType::Color, None, None),
+    ("char_color", OptionType::String, None, None),
+    ("rainbow", OptionType::Boolean, None, None),
+    ("avatar", OptionType::Integer, Some(1), None),
+    ("background", OptionType::Color, None, None),
+    ("shadow", OptionType::String, None, None),
+    ("jpeg_bg", OptionType::Color, None, None),
+    ("flip", OptionType::String, None, None),
+    ("tile", OptionType::String, None, None),
+    ("gap", OptionType::Integer, Some(0), None),
+    ("max_bytes", OptionType::Integer, Some(1), None),
+    ("depth", OptionType::Integer, Some(8), Some(16)),
+    ("animate", OptionType::Boolean, None, None),
+];
+
+pub fn get_capabilities(config: &Config) -> Capabilities {
+    let options = LOGO_OPTIONS
+        .iter()
+        .map(|&(name, kind, minimum, maximum)| {
+            let mut option = LogoOption {
+                name,
+                kind,
+                minimum,
+                maximum,
+                values: vec![],
+            };
+            match name {
+                "format" => {
+                    option.values = FORMATS.iter().map(|&format| format.to_owned()).collect()
+                }
+                "preset" => option.values = config.preset_names(),
+                "flip" => option.values = vec!["h".to_owned(), "v".to_owned(), "hv".to_owned()],
+                "depth" => option.values = vec!["8".to_owned(), "16".to_owned()],
+                "size" => {
+                    option.values = config.allowed_sizes.0.iter().map(u32::to_string).collect()
+                }
+                "avatar" => option.maximum = Some(config.max_image_size),
+                _ => {}
+            }
+            option
+        })
+        .collect();
+
+    Capabilities {
+        formats: FORMATS,
+        options,
+        limits: Limits {
+            max_history_rows: config.max_history_rows,
+            max_image_size: config.max_image_size,
+            max_body_size: config.max_body_size,
+            max_shadow_blur: logo::MAX_SHADOW_BLUR,
+        },
+        features: Features {
+            websocket: config.enable_websocket,
+            // The service can't run without the database the history is stored in
+            history: true,
+            thumbnails: config.thumbnail_width > 0,
+            mjpeg: config.max_mjpeg_clients > 0,
+            transitions: config.enable_transitions,
+        },
+    }
+}
@@ -0,0 +1,240 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use warp::{
+    self,
+    http::{header::HeaderName, HeaderMap},
+    Filter, Rejection,
+};
+
+use crate::config::{Config, ProxyHeader};
+use crate::connection;
+
+/// Extracts the address of the client, or `None` if it is not known.
+///
+/// The forwarding header is only used when `config.trust_proxy` is set, as anyone can send it, and
+/// only the `config.trust_proxy_header` that the proxies set, as the other one comes from the client
+/// as it is. Each trusted proxy appends the address it got the request from, so the client is the
+/// entry that the outermost trusted proxy added, and anything before it may be spoofed.
+pub fn client_ip(
+    config: Arc<Config>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone + Send + Sync + 'static {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .and_then(move |remote: Option<SocketAddr>, headers: HeaderMap| {
+            // The server accepts the connections itself, so warp doesn't know their address
+            let remote = remote.or_else(connection::peer_addr);
+            Ok::<_, Rejection>(resolve(&config, remote, &headers))
+        })
+}
+
+fn resolve(config: &Config, remote: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+    let peer = remote.map(|addr| addr.ip());
+    if config.trust_proxy == 0 {
+        return peer;
+    }
+
+    let forwarded = forwarded_for(headers, config.trust_proxy_header);
+    if forwarded.is_empty() {
+        return peer;
+    }
+    let client = &forwarded[forwarded.len().saturating_sub(config.trust_proxy)];
+    parse_node(client).or(peer)
+}
+
+/// The forwarded-for addresses of the request in `header`
+fn forwarded_for(headers: &HeaderMap, header: ProxyHeader) -> Vec<String> {
+    match header {
+        ProxyHeader::XForwardedFor => header_list(headers, "x-forwarded-for"),
+        ProxyHeader::Forwarded => header_list(headers, "forwarded")
+            .iter()
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let mut pair = pair.splitn(2, '=');
+                    let key = pair.next()?.trim();
+                    let value = pair.next()?.trim();
+                    if key.eq_ignore_ascii_case("for") {
+                        Some(value.trim_matches('"').to_owned())
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect(),
+    }
+}
+
+/// The comma separated values of every instance of a header, in order
+fn header_list(headers: &HeaderMap, name: &'static str) -> Vec<String> {
+    headers
+        .get_all(HeaderName::from_static(name))
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Parses an address like `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `[2001:db8::1]:4711`.
+/// Obfuscated identifiers and `unknown` give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_with(
+        trust_proxy: usize,
+        trust_proxy_header: ProxyHeader,
+        headers: &[(&'static str, &'static str)],
+    ) -> Option<IpAddr> {
+        let config = Config {
+            trust_proxy,
+            trust_proxy_header,
+            ..Config::default()
+        };
+        let mut header_map = HeaderMap::new();
+        for &(name, value) in headers {
+            header_map.append(name, value.parse().unwrap());
+        }
+        resolve(&config, Some("10.0.0.1:4711".parse().unwrap()), &header_map)
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_the_headers_without_trusted_proxies() {
+        let headers = [
+            ("x-forwarded-for", "192.0.2.1"),
+            ("forwarded", "for=192.0.2.2"),
+        ];
+        assert_eq!(
+            resolve_with(0, ProxyHeader::XForwardedFor, &headers),
+            ip("10.0.0.1")
+        );
+        assert_eq!(
+            resolve_with(0, ProxyHeader::Forwarded, &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn takes_the_address_the_outermost_trusted_proxy_added() {
+        let xff = ProxyHeader::XForwardedFor;
+        assert_eq!(
+            resolve_with(1, xff, &[("x-forwarded-for", "192.0.2.1")]),
+            ip("192.0.2.1")
+        );
+        // The client can send a header of its own, which the proxy appends to
+        assert_eq!(
+            resolve_with(1, xff, &[("x-forwarded-for", "198.51.100.7, 192.0.2.1")]),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolve_with(
+                2,
+                xff,
+                &[("x-forwarded-for", "198.51.100.7, 192.0.2.1, 172.16.0.1")]
+            ),
+            ip("192.0.2.1")
+        );
+        // Proxies can send separate headers instead of appending
+        assert_eq!(
+            resolve_with(
+                2,
+                xff,
+                &[
+                    ("x-forwarded-for", "198.51.100.7"),
+                    ("x-forwarded-for", "192.0.2.1"),
+                    ("x-forwarded-for", "172.16.0.1"),
+                ]
+            ),
+            ip("192.0.2.1")
+        );
+        assert_eq!(resolve_with(1, xff, &[]), ip("10.0.0.1"));
+        assert_eq!(
+            resolve_with(1, xff, &[("x-forwarded-for", "unknown")]),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn only_reads_the_configured_header() {
+        // A `Forwarded` header can only come from the client when the proxy sets `X-Forwarded-For`
+        let headers = [
+            ("forwarded", "for=198.51.100.7"),
+            ("x-forwarded-for", "192.0.2.1"),
+        ];
+        assert_eq!(
+            resolve_with(1, ProxyHeader::XForwardedFor, &headers),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolve_with(
+                1,
+                ProxyHeader::XForwardedFor,
+                &[("forwarded", "for=198.51.100.7")]
+            ),
+            ip("10.0.0.1")
+        );
+
+        let headers = [
+            ("forwarded", "for=192.0.2.1;proto=https"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ];
+        assert_eq!(
+            resolve_with(1, ProxyHeader::Forwarded, &headers),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolve_with(
+                1,
+                ProxyHeader::Forwarded,
+                &[("x-forwarded-for", "198.51.100.7")]
+            ),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_and_ports() {
+        let forwarded = ProxyHeader::Forwarded;
+        assert_eq!(
+            resolve_with(1, forwarded, &[("forwarded", "for=\"[2001:db8::1]:4711\"")]),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve_with(1, forwarded, &[("forwarded", "for=\"[2001:db8::1]\"")]),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve_with(
+                1,
+                forwarded,
+                &[(
+                    "forwarded",
+                    "for=198.51.100.7, proto=https;for=192.0.2.1:4711"
+                )]
+            ),
+            ip("192.0.2.1")
+        );
+
+        let xff = ProxyHeader::XForwardedFor;
+        assert_eq!(
+            resolve_with(1, xff, &[("x-forwarded-for", "2001:db8::2")]),
+            ip("2001:db8::2")
+        );
+        assert_eq!(
+            resolve_with(1, xff, &[("x-forwarded-for", "192.0.2.1:4711")]),
+            ip("192.0.2.1")
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use parking_lot::{Condvar, Mutex};
+
+/// The result of a render, shared by everyone who waited for it
+type SharedResult = Result<Vec<u8>, String>;
+
+/// A render that is running, which requests for the same image wait for
+#[derive(Default)]
+struct Call {
+    result: Mutex<Option<SharedResult>>,
+    done: Condvar,
+}
+
+/// Runs one render at a time for each key, handing its result to the requests that asked for the
+/// same key while it ran. Renders for different keys run at the same time.
+#[derive(Default)]
+pub struct Coalescer {
+    in_flight: Mutex<HashMap<String, Arc<Call>>>,
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders with `render`, unless a render for `key` is already running, in which case its
+    /// result is returned when it is done
+    pub fn run<F>(&self, key: String, render: F) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        F: FnOnce() -> Result<Vec<u8>, Box<dyn Error>>,
+    {
+        let call = {
+            let mut in_flight = self.in_flight.lock();
+            match in_flight.get(&key) {
+                Some(call) => Some(call.clone()),
+                None => {
+                    in_flight.insert(key.clone(), Arc::new(Call::default()));
+                    None
+                }
+            }
+        };
+
+        match call {
+            Some(call) => {
+                let mut result = call.result.lock();
+                loop {
+                    if let Some(ref result) = *result {
+                        return result.clone().map_err(Into::into);
+                    }
+                    call.done.wait(&mut result);
+                }
+            }
+            None => {
+                let finish = Finish {
+                    coalescer: self,
+                    key,
+                    result: None,
+                };
+                let result = render();
+                finish.with(match result {
+                    Ok(ref image) => Ok(image.clone()),
+                    Err(ref err) => Err(err.to_string()),
+                });
+                result
+            }
+        }
+    }
+}
+
+/// Hands the result of a render to the requests waiting for it, even if the render panics
+struct Finish<'a> {
+    coalescer: &'a Coalescer,
+    key: String,
+    result: Option<SharedResult>,
+}
+
+impl Finish<'_> {
+    fn with(mut self, result: SharedResult) {
+        self.result = Some(result);
+    }
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err("The render failed".to_owned()));
+        // Requests from now on render again, so they see changes made after this render started
+        if let Some(call) = self.coalescer.in_flight.lock().remove(&self.key) {
+            *call.result.lock() = Some(result);
+            call.done.notify_all();
+        }
+    }
+}
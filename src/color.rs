@@ -0,0 +1,73 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer};
+
+/// An RGBA color, written as `rrggbb` or `rrggbbaa` in hex with an optional leading `#`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    /// The color without its transparency
+    pub fn opaque(self) -> Color {
+        let [r, g, b, _] = self.0;
+        Color([r, g, b, 255])
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(color: &str) -> Result<Self, Self::Err> {
+        let hex = color.trim_start_matches('#');
+        let invalid = || format!("{} is not a valid color", color);
+        let channel = |index: usize| {
+            hex.get(index * 2..index * 2 + 2)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+                .ok_or_else(invalid)
+        };
+
+        match hex.len() {
+            6 => Ok(Color([channel(0)?, channel(1)?, channel(2)?, 255])),
+            8 => Ok(Color([channel(0)?, channel(1)?, channel(2)?, channel(3)?])),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    /// Writes the color as `#rrggbb`, or `#rrggbbaa` if it is not opaque
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b, a] = self.0;
+        write!(f, "#{:02x}{:02x}{:02x}", r, g, b)?;
+        if a != 255 {
+            write!(f, "{:02x}", a)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Colors in order, written as colors separated by commas, or as a list in the config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Palette(pub Vec<Color>);
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(palette: &str) -> Result<Self, Self::Err> {
+        palette
+            .split(',')
+            .map(|color| color.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Palette)
+    }
+}
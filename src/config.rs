@@ -0,0 +1,637 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use serde::Deserialize;
+use snafu::{ensure, ResultExt, Snafu};
+use warp::http::header::{HeaderName, HeaderValue};
+
+use crate::color::{Color, Palette};
+use crate::logo::Format;
+
+/// Path of the config file used when `CONFIG_FILE` is not set
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Image sent when the logo can't be rendered, unless `error_image` is set
+const BUILTIN_ERROR_IMAGE: &[u8] = include_bytes!("error.png");
+
+/// `error_image` that sends responses without a body
+const NO_ERROR_IMAGE: &str = "none";
+
+/// Red to violet, a color for each character
+const DEFAULT_RAINBOW: &str = "ff0000,ff7f00,ffff00,00ff00,0000ff,4b0082,9400d3";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read config file {}: {}", path, source))]
+    ReadFile { path: String, source: io::Error },
+    #[snafu(display("Could not parse config file {}: {}", path, source))]
+    ParseFile {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Invalid value {:?} for {}: {}", value, env, message))]
+    InvalidEnvVar {
+        env: String,
+        value: String,
+        message: String,
+    },
+    #[snafu(display("database_url must be set in the config file or with DATABASE_URL"))]
+    MissingDatabaseUrl,
+    #[snafu(display("{} can't be sent in a header: {:?}", key, value))]
+    InvalidHeaderValue { key: String, value: String },
+    // The value is left out, as it is a secret
+    #[snafu(display(
+        "upstream_auth_header must be a header name, and upstream_auth_value a header value"
+    ))]
+    InvalidUpstreamAuth,
+    #[snafu(display("Could not read error_image {}: {}", path, source))]
+    ReadErrorImage { path: String, source: io::Error },
+    #[snafu(display("error_image {} is not a PNG", path))]
+    InvalidErrorImage { path: String },
+    #[snafu(display(
+        "error_status {} is not an HTTP status code that can answer with an image",
+        status
+    ))]
+    InvalidErrorStatus { status: u16 },
+}
+
+/// What to do with a websocket listener that has fallen `listener_buffer` updates behind
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowListenerPolicy {
+    /// Drop the oldest queued update to make room for the new one
+    DropOldest,
+    /// Drop the new update
+    DropUpdate,
+    /// Close the connection
+    Disconnect,
+}
+
+impl FromStr for SlowListenerPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(SlowListenerPolicy::DropOldest),
+            "drop_update" => Ok(SlowListenerPolicy::DropUpdate),
+            "disconnect" => Ok(SlowListenerPolicy::Disconnect),
+            _ => Err("expected drop_oldest, drop_update or disconnect".to_owned()),
+        }
+    }
+}
+
+/// The header that the trusted proxies add the client address to
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyHeader {
+    /// `X-Forwarded-For: 192.0.2.1`
+    XForwardedFor,
+    /// `Forwarded: for=192.0.2.1`, from RFC 7239
+    Forwarded,
+}
+
+impl FromStr for ProxyHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "x_forwarded_for" => Ok(ProxyHeader::XForwardedFor),
+            "forwarded" => Ok(ProxyHeader::Forwarded),
+            _ => Err("expected x_forwarded_for or forwarded".to_owned()),
+        }
+    }
+}
+
+/// What to do with a logo from the upstream that has more characters than fit in the image
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedLogo {
+    /// Draw the characters that fit, leaving out the rest
+    Truncate,
+    /// Fail to render it, which serves the error image
+    Error,
+}
+
+impl FromStr for OversizedLogo {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "truncate" => Ok(OversizedLogo::Truncate),
+            "error" => Ok(OversizedLogo::Error),
+            _ => Err("expected truncate or error".to_owned()),
+        }
+    }
+}
+
+/// Values of `size` that can be requested, written as numbers separated by commas, or as a list in
+/// the config file. Empty allows any size.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Sizes(pub Vec<u32>);
+
+impl Sizes {
+    pub fn allows(&self, size: u32) -> bool {
+        self.0.is_empty() || self.0.contains(&size)
+    }
+
+    /// The allowed size closest to `size`, preferring the smaller of two that are as close
+    pub fn nearest(&self, size: u32) -> u32 {
+        self.0
+            .iter()
+            .cloned()
+            .min_by_key(|&allowed| (allowed.abs_diff(size), allowed))
+            .unwrap_or(size)
+    }
+}
+
+impl fmt::Display for Sizes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sizes: Vec<_> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", sizes.join(", "))
+    }
+}
+
+impl FromStr for Sizes {
+    type Err = String;
+
+    fn from_str(sizes: &str) -> Result<Self, Self::Err> {
+        sizes
+            .split(',')
+            .map(str::trim)
+            .filter(|size| !size.is_empty())
+            .map(|size| size.parse().map_err(|_| format!("{} is not a size", size)))
+            .collect::<Result<_, _>>()
+            .map(Sizes)
+    }
+}
+
+/// A color transform applied to every pixel of the logo, as `matrix * rgb + offset`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    /// Rows giving the new red, green and blue from the old red, green and blue
+    pub matrix: [[f32; 3]; 3],
+    #[serde(default)]
+    pub offset: [f32; 3],
+}
+
+/// Names of the presets that are available without being configured
+const BUILTIN_PRESETS: [&str; 3] = ["grayscale", "sepia", "highcontrast"];
+
+/// Presets that are available without being configured
+fn builtin_preset(name: &str) -> Option<Preset> {
+    match name {
+        "grayscale" => Some(Preset {
+            matrix: [[0.299, 0.587, 0.114]; 3],
+            offset: [0.0; 3],
+        }),
+        "sepia" => Some(Preset {
+            matrix: [
+                [0.393, 0.769, 0.189],
+                [0.349, 0.686, 0.168],
+                [0.272, 0.534, 0.131],
+            ],
+            offset: [0.0; 3],
+        }),
+        "highcontrast" => Some(Preset {
+            matrix: [[1.6, 0.0, 0.0], [0.0, 1.6, 0.0], [0.0, 0.0, 1.6]],
+            offset: [-76.8; 3],
+        }),
+        _ => None,
+    }
+}
+
+/// How the upstream is polled for the live logo
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PollStrategy {
+    /// Download the logo on every poll
+    Full,
+    /// Send the upstream's `ETag` and `Last-Modified` back, so it can answer 304 when the logo
+    /// has not changed
+    Conditional,
+}
+
+impl FromStr for PollStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "full" => Ok(PollStrategy::Full),
+            "conditional" => Ok(PollStrategy::Conditional),
+            _ => Err("expected full or conditional".to_owned()),
+        }
+    }
+}
+
+/// All the knobs of the service.
+///
+/// Values are read from an optional TOML file (`config.toml`, or the path in `CONFIG_FILE`), and
+/// each of them can be overridden with the environment variable noted on the field.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the HTTP server listens on (`BIND_ADDR`, with the port overridden by `PORT`)
+    pub bind_addr: SocketAddr,
+    /// Seconds an HTTP connection may go without sending or receiving anything before it is
+    /// closed, where 0 keeps it open (`IDLE_TIMEOUT`)
+    pub idle_timeout: u64,
+    /// Postgres connection string (`DATABASE_URL`)
+    pub database_url: String,
+    /// The logo-api endpoint that is polled for the live logo (`UPSTREAM_URL`)
+    pub upstream_url: String,
+    /// File of captured logos to replay instead of polling the upstream, one json logo per line
+    /// (`REPLAY_FILE`)
+    pub replay_file: String,
+    /// Seconds to wait between each poll of the upstream (`POLL_INTERVAL`)
+    pub poll_interval: u64,
+    /// How the upstream is polled (`POLL_STRATEGY`)
+    pub poll_strategy: PollStrategy,
+    /// Header sent to the upstream to authenticate, where empty sends none
+    /// (`UPSTREAM_AUTH_HEADER`)
+    pub upstream_auth_header: String,
+    /// Value of `upstream_auth_header`, like `Bearer <token>` (`UPSTREAM_AUTH_VALUE`)
+    pub upstream_auth_value: String,
+    /// Origin allowed to call the api from a browser (`CORS_ORIGIN`)
+    pub cors_origin: String,
+    /// Milliseconds a database query may run before it is cancelled (`DB_STATEMENT_TIMEOUT`)
+    pub db_statement_timeout: u64,
+    /// Seconds to keep trying to connect to the database at startup, where 0 gives up after the
+    /// first try (`DB_STARTUP_TIMEOUT`)
+    pub db_startup_timeout: u64,
+    /// Milliseconds `/api/v1/history` and the history search may take before they are abandoned
+    /// with a 503, where 0 is no limit. The export takes as long as the client, and is bounded by
+    /// `max_exports` instead (`HISTORY_TIME_BUDGET`)
+    pub history_time_budget: u64,
+    /// Milliseconds rendering a logo sent to the render api may take before it is abandoned with
+    /// a 503, where 0 is no limit (`RENDER_TIME_BUDGET`)
+    pub render_time_budget: u64,
+    /// Serve live updates on the `/live` websocket (`ENABLE_WEBSOCKET`)
+    pub enable_websocket: bool,
+    /// Serve Lottie animations of changes at `/api/v1/logo/transition.json`
+    /// (`ENABLE_TRANSITIONS`)
+    pub enable_transitions: bool,
+    /// Updates that can be queued for a websocket listener before it counts as slow
+    /// (`LISTENER_BUFFER`)
+    pub listener_buffer: usize,
+    /// What to do with slow websocket listeners (`SLOW_LISTENER_POLICY`)
+    pub slow_listener_policy: SlowListenerPolicy,
+    /// Messages per second a websocket listener may send on average before it is disconnected,
+    /// where 0 allows any number (`LISTENER_MESSAGE_RATE`)
+    pub listener_message_rate: u32,
+    /// Milliseconds that changes to the logo are gathered for before the latest of them is sent to
+    /// the websocket listeners, where 0 sends every change right away (`BROADCAST_WINDOW`)
+    pub broadcast_window: u64,
+    /// Clients that `/logo.mjpeg` streams to at once, where 0 turns it off (`MAX_MJPEG_CLIENTS`)
+    pub max_mjpeg_clients: usize,
+    /// History exports that can run at once, each holding a database connection for as long as
+    /// it takes the client, where 0 turns them off (`MAX_EXPORTS`)
+    pub max_exports: usize,
+    /// History playbacks that can run at once, each on a thread of its own that reads the
+    /// history, where 0 turns them off (`MAX_PLAYBACKS`)
+    pub max_playbacks: usize,
+    /// Number of reverse proxies in front of the service whose `trust_proxy_header` is trusted
+    /// for the client address, where 0 ignores the headers (`TRUST_PROXY`)
+    pub trust_proxy: usize,
+    /// The header the proxies add the client address to, where the other one is never read
+    /// (`TRUST_PROXY_HEADER`)
+    pub trust_proxy_header: ProxyHeader,
+    /// Most entries the history api returns at once, where 0 is no limit (`MAX_HISTORY_ROWS`)
+    pub max_history_rows: u32,
+    /// Largest request body accepted by the POST routes, in bytes (`MAX_BODY_SIZE`)
+    pub max_body_size: u64,
+    /// Largest size a gzipped request body may decompress to, in bytes
+    /// (`MAX_DECOMPRESSED_SIZE`)
+    pub max_decompressed_size: u64,
+    /// Largest width or height of an image that can be requested, in pixels (`MAX_IMAGE_SIZE`)
+    pub max_image_size: u32,
+    /// Values of `size` that can be requested, where none allows any (`ALLOWED_SIZES`)
+    pub allowed_sizes: Sizes,
+    /// Color that transparent pixels become in JPEGs, unless the request sets `jpeg_bg`
+    /// (`JPEG_BACKGROUND`)
+    pub jpeg_background: Color,
+    /// Format of the logo when the request doesn't ask for one (`DEFAULT_FORMAT`)
+    pub default_format: Format,
+    /// Colors that `rainbow` gives the characters, from the first (`RAINBOW_PALETTE`)
+    pub rainbow_palette: Palette,
+    /// What to do with a logo that has more characters than fit (`OVERSIZED_LOGO`)
+    pub oversized_logo: OversizedLogo,
+    /// Color presets by name, in addition to (or replacing) the built-in ones. Only set in the
+    /// config file.
+    pub presets: HashMap<String, Preset>,
+    /// Width of the thumbnail stored with each new image in the history, where 0 stores none
+    /// (`THUMBNAIL_WIDTH`)
+    pub thumbnail_width: u32,
+    /// Least seconds between the changes stored in the history, where 0 stores every change. The
+    /// latest change is stored when they have passed (`HISTORY_INTERVAL`)
+    pub history_interval: u64,
+    /// Mark PNGs as sRGB, so viewers show the same colors (`PNG_SRGB`)
+    pub png_srgb: bool,
+    /// Average colors as light when scaling the logo down, which keeps the edges from getting
+    /// darker but is slower (`LINEAR_SCALING`)
+    pub linear_scaling: bool,
+    /// Path of the PNG sent when the logo can't be rendered, where empty sends the built-in one and
+    /// `none` sends no body (`ERROR_IMAGE`)
+    pub error_image: String,
+    /// Status of the responses with `error_image` (`ERROR_STATUS`)
+    pub error_status: u16,
+    /// The bytes of `error_image`, read when the config is loaded
+    #[serde(skip)]
+    pub error_image_data: Arc<Vec<u8>>,
+    /// `Content-Security-Policy` of the HTML pages, where an empty policy sends none
+    /// (`CONTENT_SECURITY_POLICY`)
+    pub content_security_policy: String,
+    /// `X-Frame-Options` of the HTML pages, where empty sends none (`FRAME_OPTIONS`)
+    pub frame_options: String,
+    /// `Referrer-Policy` of the HTML pages, where empty sends none (`REFERRER_POLICY`)
+    pub referrer_policy: String,
+    /// How many times the config has been reloaded, so what is derived from an older one can be
+    /// told apart
+    #[serde(skip)]
+    pub generation: u64,
+}
+
+impl Config {
+    /// Finds a configured or built-in preset
+    pub fn preset(&self, name: &str) -> Option<Preset> {
+        self.presets
+            .get(name)
+            .cloned()
+            .or_else(|| builtin_preset(name))
+    }
+
+    /// Names of the configured and built-in presets, sorted
+    pub fn preset_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.presets.keys().cloned().collect();
+        for name in &BUILTIN_PRESETS {
+            if !self.presets.contains_key(*name) {
+                names.push((*name).to_owned());
+            }
+        }
+        names.sort();
+        names
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: ([0, 0, 0, 0], 3000).into(),
+            idle_timeout: 60,
+            database_url: String::new(),
+            upstream_url: "https://logo-api.g2.iterate.no/logo".to_owned(),
+            replay_file: String::new(),
+            poll_interval: 1,
+            poll_strategy: PollStrategy::Conditional,
+            upstream_auth_header: String::new(),
+            upstream_auth_value: String::new(),
+            cors_origin: "http://localhost:8000".to_owned(),
+            db_statement_timeout: 10_000,
+            db_startup_timeout: 60,
+            history_time_budget: 30_000,
+            render_time_budget: 10_000,
+            enable_websocket: true,
+            enable_transitions: false,
+            listener_buffer: 16,
+            slow_listener_policy: SlowListenerPolicy::DropOldest,
+            listener_message_rate: 10,
+            broadcast_window: 0,
+            max_mjpeg_clients: 10,
+            max_exports: 2,
+            max_playbacks: 8,
+            trust_proxy: 0,
+            trust_proxy_header: ProxyHeader::XForwardedFor,
+            max_history_rows: 0,
+            max_body_size: 256 * 1024,
+            max_decompressed_size: 1024 * 1024,
+            max_image_size: 2048,
+            allowed_sizes: Sizes::default(),
+            jpeg_background: Color([255, 255, 255, 255]),
+            default_format: Format::Png,
+            rainbow_palette: DEFAULT_RAINBOW
+                .parse()
+                .expect("The default rainbow is valid"),
+            oversized_logo: OversizedLogo::Truncate,
+            presets: HashMap::new(),
+            thumbnail_width: 0,
+            history_interval: 0,
+            png_srgb: true,
+            linear_scaling: true,
+            error_image: String::new(),
+            error_status: 200,
+            error_image_data: Arc::new(BUILTIN_ERROR_IMAGE.to_vec()),
+            // The pages have inline scripts and styles, and show the live logo from blob urls
+            content_security_policy: "default-src 'self'; script-src 'self' 'unsafe-inline'; \
+                                      style-src 'self' 'unsafe-inline'; \
+                                      img-src 'self' blob: data:; frame-ancestors 'none'"
+                .to_owned(),
+            frame_options: "DENY".to_owned(),
+            referrer_policy: "same-origin".to_owned(),
+            generation: 0,
+        }
+    }
+}
+
+/// The running config, which can be swapped for a freshly loaded one without a restart.
+///
+/// Everything that reads the config takes a snapshot with `get` when it starts on a request, a
+/// poll or a websocket connection, so a reload applies from the next one on.
+pub struct ConfigHandle(RwLock<Arc<Config>>);
+
+impl ConfigHandle {
+    pub fn new(config: Config) -> Self {
+        ConfigHandle(RwLock::new(Arc::new(config)))
+    }
+
+    /// The current config
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().clone()
+    }
+
+    /// Loads the config again and swaps it in
+    pub fn reload(&self) -> Result<(), Error> {
+        self.replace(load()?);
+        Ok(())
+    }
+
+    /// Swaps in a new config. Settings that are only read when the service starts keep their
+    /// running values, so the config always describes what is in effect.
+    pub fn replace(&self, mut config: Config) {
+        let mut current = self.0.write();
+        config.bind_addr = current.bind_addr;
+        config.idle_timeout = current.idle_timeout;
+        config.database_url = current.database_url.clone();
+        config.upstream_url = current.upstream_url.clone();
+        config.replay_file = current.replay_file.clone();
+        config.poll_strategy = current.poll_strategy;
+        config.upstream_auth_header = current.upstream_auth_header.clone();
+        config.upstream_auth_value = current.upstream_auth_value.clone();
+        config.trust_proxy = current.trust_proxy;
+        config.trust_proxy_header = current.trust_proxy_header;
+        config.max_body_size = current.max_body_size;
+        config.generation = current.generation + 1;
+        *current = Arc::new(config);
+    }
+}
+
+/// Whether a response with the status has a body, which the error image needs. Informational
+/// statuses, 204 No Content and 304 Not Modified never do.
+fn sends_body(status: u16) -> bool {
+    (200..600).contains(&status) && status != 204 && status != 304
+}
+
+/// Loads the config file (if it exists) and applies the environment overrides on top of it.
+pub fn load() -> Result<Config, Error> {
+    let (path, required) = match env::var("CONFIG_FILE") {
+        Ok(path) => (path, true),
+        Err(_) => (DEFAULT_CONFIG_FILE.to_owned(), false),
+    };
+
+    let mut config = match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content).context(ParseFile { path })?,
+        // The default config file is optional, but one given explicitly is not
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound && !required => Config::default(),
+        Err(err) => return Err(err).context(ReadFile { path }),
+    };
+
+    override_from_env("BIND_ADDR", &mut config.bind_addr)?;
+    // Platforms like Heroku and Cloud Run tell the service which port to use in PORT
+    let mut port = config.bind_addr.port();
+    override_from_env("PORT", &mut port)?;
+    config.bind_addr.set_port(port);
+    override_from_env("IDLE_TIMEOUT", &mut config.idle_timeout)?;
+    override_from_env("UPSTREAM_URL", &mut config.upstream_url)?;
+    override_from_env("REPLAY_FILE", &mut config.replay_file)?;
+    override_from_env("POLL_INTERVAL", &mut config.poll_interval)?;
+    override_from_env("POLL_STRATEGY", &mut config.poll_strategy)?;
+    override_from_env("UPSTREAM_AUTH_HEADER", &mut config.upstream_auth_header)?;
+    override_from_env("UPSTREAM_AUTH_VALUE", &mut config.upstream_auth_value)?;
+    override_from_env("CORS_ORIGIN", &mut config.cors_origin)?;
+    override_from_env("DATABASE_URL", &mut config.database_url)?;
+    override_from_env("DB_STATEMENT_TIMEOUT", &mut config.db_statement_timeout)?;
+    override_from_env("DB_STARTUP_TIMEOUT", &mut config.db_startup_timeout)?;
+    override_from_env("HISTORY_TIME_BUDGET", &mut config.history_time_budget)?;
+    override_from_env("RENDER_TIME_BUDGET", &mut config.render_time_budget)?;
+    override_from_env("ENABLE_WEBSOCKET", &mut config.enable_websocket)?;
+    override_from_env("ENABLE_TRANSITIONS", &mut config.enable_transitions)?;
+    override_from_env("LISTENER_BUFFER", &mut config.listener_buffer)?;
+    override_from_env("SLOW_LISTENER_POLICY", &mut config.slow_listener_policy)?;
+    override_from_env("LISTENER_MESSAGE_RATE", &mut config.listener_message_rate)?;
+    override_from_env("BROADCAST_WINDOW", &mut config.broadcast_window)?;
+    override_from_env("MAX_MJPEG_CLIENTS", &mut config.max_mjpeg_clients)?;
+    override_from_env("MAX_EXPORTS", &mut config.max_exports)?;
+    override_from_env("MAX_PLAYBACKS", &mut config.max_playbacks)?;
+    override_from_env("TRUST_PROXY", &mut config.trust_proxy)?;
+    override_from_env("TRUST_PROXY_HEADER", &mut config.trust_proxy_header)?;
+    override_from_env("MAX_HISTORY_ROWS", &mut config.max_history_rows)?;
+    override_from_env("MAX_BODY_SIZE", &mut config.max_body_size)?;
+    override_from_env("MAX_DECOMPRESSED_SIZE", &mut config.max_decompressed_size)?;
+    override_from_env("MAX_IMAGE_SIZE", &mut config.max_image_size)?;
+    override_from_env("ALLOWED_SIZES", &mut config.allowed_sizes)?;
+    override_from_env("JPEG_BACKGROUND", &mut config.jpeg_background)?;
+    override_from_env("DEFAULT_FORMAT", &mut config.default_format)?;
+    override_from_env("RAINBOW_PALETTE", &mut config.rainbow_palette)?;
+    override_from_env("OVERSIZED_LOGO", &mut config.oversized_logo)?;
+    override_from_env("THUMBNAIL_WIDTH", &mut config.thumbnail_width)?;
+    override_from_env("HISTORY_INTERVAL", &mut config.history_interval)?;
+    override_from_env("PNG_SRGB", &mut config.png_srgb)?;
+    override_from_env("LINEAR_SCALING", &mut config.linear_scaling)?;
+    override_from_env("ERROR_IMAGE", &mut config.error_image)?;
+    override_from_env("ERROR_STATUS", &mut config.error_status)?;
+    override_from_env(
+        "CONTENT_SECURITY_POLICY",
+        &mut config.content_security_policy,
+    )?;
+    override_from_env("FRAME_OPTIONS", &mut config.frame_options)?;
+    override_from_env("REFERRER_POLICY", &mut config.referrer_policy)?;
+
+    for (key, value) in &[
+        ("content_security_policy", &config.content_security_policy),
+        ("frame_options", &config.frame_options),
+        ("referrer_policy", &config.referrer_policy),
+    ] {
+        ensure!(
+            HeaderValue::from_str(value).is_ok(),
+            InvalidHeaderValue {
+                key: *key,
+                value: value.as_str()
+            }
+        );
+    }
+    ensure!(
+        config.upstream_auth_header.is_empty()
+            || (HeaderName::from_bytes(config.upstream_auth_header.as_bytes()).is_ok()
+                && HeaderValue::from_str(&config.upstream_auth_value).is_ok()),
+        InvalidUpstreamAuth
+    );
+    ensure!(
+        sends_body(config.error_status),
+        InvalidErrorStatus {
+            status: config.error_status
+        }
+    );
+    config.error_image_data = Arc::new(read_error_image(&config.error_image)?);
+
+    Ok(config)
+}
+
+/// Reads the image given in `error_image`
+fn read_error_image(path: &str) -> Result<Vec<u8>, Error> {
+    match path {
+        "" => Ok(BUILTIN_ERROR_IMAGE.to_vec()),
+        NO_ERROR_IMAGE => Ok(vec![]),
+        _ => {
+            let image = fs::read(path).context(ReadErrorImage { path })?;
+            // It is always sent as a PNG
+            ensure!(
+                image.starts_with(b"\x89PNG\r\n\x1a\n"),
+                InvalidErrorImage { path }
+            );
+            Ok(image)
+        }
+    }
+}
+
+/// Checks that a database is configured, which everything but rendering a single logo needs
+pub fn require_database(config: &Config) -> Result<(), Error> {
+    ensure!(!config.database_url.is_empty(), MissingDatabaseUrl);
+    Ok(())
+}
+
+fn override_from_env<T>(env: &str, target: &mut T) -> Result<(), Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = env::var(env) {
+        *target = value.parse().map_err(|err: T::Err| Error::InvalidEnvVar {
+            env: env.to_owned(),
+            value: value.clone(),
+            message: err.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_takes_error_statuses_with_a_body() {
+        for status in &[200, 203, 404, 500, 503, 599] {
+            assert!(sends_body(*status), "{}", status);
+        }
+        for status in &[0, 100, 101, 199, 204, 304, 600, 999] {
+            assert!(!sends_body(*status), "{}", status);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{task, task_local, Async, Future, Poll, Stream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Delay;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// How long to wait before accepting again when accepting fails, like when the process is out of
+/// file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+task_local! {
+    // Address of the client on the connection the current task serves
+    static PEER_ADDR: Cell<Option<SocketAddr>> = Cell::new(None)
+}
+
+/// The address of the client on the connection the current request came in on.
+///
+/// Warp only knows the address of the connections it accepts itself, so it is passed on from the
+/// connection to the request through the task that serves them both.
+pub fn peer_addr() -> Option<SocketAddr> {
+    if task::is_in_task() {
+        PEER_ADDR.with(Cell::get)
+    } else {
+        None
+    }
+}
+
+/// Accepts the connections to the HTTP server
+pub struct Incoming {
+    listener: TcpListener,
+    idle_timeout: Option<Duration>,
+    error_delay: Option<Delay>,
+}
+
+impl Incoming {
+    /// Listens on `addr`, closing connections that are idle for longer than `idle_timeout`
+    pub fn bind(addr: &SocketAddr, idle_timeout: Option<Duration>) -> io::Result<Self> {
+        Ok(Incoming {
+            listener: TcpListener::bind(addr)?,
+            idle_timeout,
+            error_delay: None,
+        })
+    }
+}
+
+impl Stream for Incoming {
+    type Item = Connection;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(ref mut delay) = self.error_delay {
+                match delay.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) | Err(_) => self.error_delay = None,
+                }
+            }
+
+            match self.listener.poll_accept() {
+                Ok(Async::Ready((stream, peer_addr))) => {
+                    // Like warp does for the connections it accepts
+                    let _ = stream.set_nodelay(true);
+                    return Ok(Async::Ready(Some(Connection::new(
+                        stream,
+                        peer_addr,
+                        self.idle_timeout,
+                    ))));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // The server stops if this returns an error, so keep trying instead
+                Err(err) => {
+                    eprintln!("Error accepting connection: {}", err);
+                    self.error_delay = Some(Delay::new(Instant::now() + ACCEPT_ERROR_DELAY));
+                }
+            }
+        }
+    }
+}
+
+/// A connection to the HTTP server that fails to read once nothing has been sent or received for
+/// `idle_timeout`, so the server closes it.
+///
+/// Websockets are left open, as listeners can go a long time without either, and look after
+/// themselves in `live`.
+pub struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    idle_timeout: Option<Duration>,
+    last_active: Instant,
+    deadline: Option<Delay>,
+    upgraded: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer_addr: SocketAddr, idle_timeout: Option<Duration>) -> Self {
+        Connection {
+            stream,
+            peer_addr,
+            idle_timeout,
+            last_active: Instant::now(),
+            deadline: None,
+            upgraded: false,
+        }
+    }
+
+    /// Whether the connection has been idle for too long. When it hasn't, the current task is
+    /// woken up when it would be.
+    fn timed_out(&mut self) -> io::Result<bool> {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) if !self.upgraded => idle_timeout,
+            _ => return Ok(false),
+        };
+
+        let deadline = self.last_active + idle_timeout;
+        if Instant::now() >= deadline {
+            return Ok(true);
+        }
+        let delay = self.deadline.get_or_insert_with(|| Delay::new(deadline));
+        if delay.deadline() != deadline {
+            delay.reset(deadline);
+        }
+        match delay.poll() {
+            Ok(Async::Ready(())) => Ok(Instant::now() >= deadline),
+            Ok(Async::NotReady) => Ok(false),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        PEER_ADDR.with(|peer_addr| peer_addr.set(Some(self.peer_addr)));
+
+        match self.stream.read(buf) {
+            Ok(read) => {
+                self.last_active = Instant::now();
+                Ok(read)
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if self.timed_out()? {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection was idle for too long",
+                    ))
+                } else {
+                    Err(io::ErrorKind::WouldBlock.into())
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Responses start on a fresh write, and a websocket starts with its 101 response
+        if buf.starts_with(b"HTTP/1.1 101 ") {
+            self.upgraded = true;
+        }
+        let written = self.stream.write(buf)?;
+        self.last_active = Instant::now();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
@@ -1,22 +1,133 @@
-use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
 
-use base64;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, SecondsFormat, TimeZone, Utc};
+use fallible_iterator::FallibleIterator;
 use flate2::{write::GzEncoder, Compression};
-use postgres::{Connection, TlsMode};
-use serde::{Deserialize, Serialize, Serializer};
-use serde_json;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use hyper::Body;
+use postgres::{
+    error::{CANNOT_CONNECT_NOW, QUERY_CANCELED},
+    rows::Rows,
+    types::ToSql,
+    Connection, TlsMode,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use snafu::{ResultExt, Snafu};
 use warp::{
     http::{self, Response},
     reply,
 };
 
+use crate::budget::{self, Budget};
+use crate::color::Color;
+use crate::config::Config;
+use crate::{logo, metrics};
+
+/// How times are written in the json of the history api
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeFormat {
+    #[default]
+    Rfc3339,
+    /// Milliseconds since the Unix epoch, as a number
+    EpochMs,
+}
+
+/// How the fields in the json of the history api are named
+#[derive(Debug, Deserialize, Copy, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Naming {
+    /// `changed_characters`, the names of the fields in Rust
+    #[default]
+    Snake,
+    /// `changedCharacters`, as JavaScript clients tend to expect
+    Camel,
+}
+
+impl Naming {
+    /// Writes `value` as json with its fields named this way
+    fn to_writer<W: Write, T: Serialize>(
+        self,
+        writer: W,
+        value: &T,
+    ) -> Result<(), serde_json::Error> {
+        match self {
+            Naming::Snake => serde_json::to_writer(writer, value),
+            Naming::Camel => {
+                let mut json = serde_json::to_value(value)?;
+                rename_to_camel_case(&mut json);
+                serde_json::to_writer(writer, &json)
+            }
+        }
+    }
+
+    fn to_vec<T: Serialize>(self, value: &T) -> Result<Vec<u8>, serde_json::Error> {
+        let mut json = Vec::new();
+        self.to_writer(&mut json, value)?;
+        Ok(json)
+    }
+}
+
+fn rename_to_camel_case(json: &mut serde_json::Value) {
+    match json {
+        serde_json::Value::Object(fields) => {
+            *fields = std::mem::take(fields)
+                .into_iter()
+                .map(|(name, mut value)| {
+                    rename_to_camel_case(&mut value);
+                    (camel_case(&name), value)
+                })
+                .collect();
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(rename_to_camel_case),
+        _ => {}
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut words = name.split('_');
+    let mut camel = words.next().unwrap_or_default().to_owned();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+/// A time that is serialized in the format chosen by the request
+#[derive(Debug, Copy, Clone)]
+struct Time {
+    time: DateTime<Utc>,
+    format: TimeFormat,
+}
+
+impl Serialize for Time {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.format {
+            TimeFormat::Rfc3339 => self.time.serialize(serializer),
+            TimeFormat::EpochMs => serializer.serialize_i64(self.time.timestamp_millis()),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct LogoState {
-    time: DateTime<Utc>,
+    time: Time,
     #[serde(serialize_with = "as_base64")]
     logo: Vec<u8>,
+    /// Bitmask of the characters that changed from the entry before, where bit n is character n
+    changed_characters: Option<i16>,
 }
 
 fn as_base64<T, S>(key: &T, serializer: S) -> Result<S::Ok, S::Error>
@@ -29,25 +140,10 @@ where
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Could not get environment variable {}", env))]
-    EnvVar {
-        env: String,
-        source: env::VarError,
-    },
     #[snafu(display("PostgresError {}", source))]
     PgError {
         source: postgres::Error,
     },
-    #[snafu(display("Error inserting {} into {}: {}", value, table, source))]
-    PgInsert {
-        table: String,
-        value: String,
-        source: postgres::Error,
-    },
-    PgQuery {
-        query: String,
-        source: postgres::Error,
-    },
     JsonError {
         source: serde_json::Error,
     },
@@ -57,45 +153,354 @@ pub enum Error {
     EncodeError {
         source: std::io::Error,
     },
+    #[snafu(display("The time is not an RFC 3339 timestamp: {}", source))]
     ParseDateError {
         source: chrono::format::ParseError,
     },
+    #[snafu(display("{}", source))]
+    OverBudget {
+        source: budget::Exceeded,
+    },
+}
+
+impl Error {
+    /// Whether the error is a query that ran past the statement timeout
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::PgError { source } => source.code() == Some(&QUERY_CANCELED),
+            _ => false,
+        }
+    }
+
+    /// Whether the request ran out of its time budget
+    pub fn is_over_budget(&self) -> bool {
+        matches!(self, Error::OverBudget { .. })
+    }
+
+    /// Whether the request asked for something that can't be read, rather than anything going
+    /// wrong with the database
+    pub fn is_bad_request(&self) -> bool {
+        matches!(self, Error::ParseDateError { .. })
+    }
+}
+
+fn connect(config: &Config) -> Result<Connection, Error> {
+    Connection::connect(config.database_url.as_str(), TlsMode::None).context(PgError)
+}
+
+/// A connection where each statement is cancelled if it runs past the configured timeout
+fn get_conn(config: &Config) -> Result<Connection, Error> {
+    get_conn_within(config, Budget::unlimited())
+}
+
+/// A connection where each statement is also cancelled if it would run past the budget
+fn get_conn_within(config: &Config, budget: Budget) -> Result<Connection, Error> {
+    budget.check().context(OverBudget)?;
+    let mut timeout = config.db_statement_timeout;
+    if let Some(remaining) = budget.remaining() {
+        // A timeout of 0 would be no timeout
+        let remaining = (remaining.as_millis() as u64).max(1);
+        timeout = if timeout == 0 {
+            remaining
+        } else {
+            timeout.min(remaining)
+        };
+    }
+    let conn = connect(config)?;
+    // NOTE: This is safe because we know that the timeout is a number
+    conn.batch_execute(&format!("SET statement_timeout = {}", timeout))
+        .context(PgError)?;
+    Ok(conn)
 }
 
-fn get_conn() -> Result<Connection, Error> {
-    let db = std::env::var("DATABASE_URL").context(EnvVar {
-        env: "DATABASE_URL",
-    })?;
-    Ok(Connection::connect(db, TlsMode::None).context(PgError)?)
+/// Runs a query of the history, timing it for the metrics
+fn query_history(
+    conn: &Connection,
+    query: &str,
+    params: &[&dyn ToSql],
+) -> Result<postgres::rows::Rows, Error> {
+    metrics::HISTORY_QUERY
+        .time(|| conn.query(query, params))
+        .context(PgError)
 }
 
-pub fn init_db() -> Result<(), Error> {
-    let conn = get_conn()?;
+/// Runs a query within the budget, where a query cancelled by it is over budget
+fn query_within(
+    conn: &Connection,
+    budget: Budget,
+    query: &str,
+    params: &[&dyn ToSql],
+) -> Result<postgres::rows::Rows, Error> {
+    query_history(conn, query, params).map_err(|err| {
+        if err.is_timeout() && budget.check().is_err() {
+            Error::OverBudget {
+                source: budget::Exceeded,
+            }
+        } else {
+            err
+        }
+    })
+}
+
+/// First wait between tries to connect at startup, which doubles up to `MAX_STARTUP_RETRY_DELAY`
+const STARTUP_RETRY_DELAY: StdDuration = StdDuration::from_millis(250);
+const MAX_STARTUP_RETRY_DELAY: StdDuration = StdDuration::from_secs(5);
+
+/// Whether connecting failed because the database can't be reached or is still starting, rather
+/// than because it refused the connection
+fn is_unavailable(err: &postgres::Error) -> bool {
+    err.as_io().is_some()
+        || err.as_connection().is_some()
+        || err.code() == Some(&CANNOT_CONNECT_NOW)
+}
+
+/// Connects, trying again for up to `db_startup_timeout` while the database is unavailable, as it
+/// may be starting up alongside the service
+fn connect_on_startup(config: &Config) -> Result<Connection, Error> {
+    let deadline = Instant::now() + StdDuration::from_secs(config.db_startup_timeout);
+    let mut delay = STARTUP_RETRY_DELAY;
+    loop {
+        match Connection::connect(config.database_url.as_str(), TlsMode::None) {
+            Ok(conn) => return Ok(conn),
+            Err(err) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if !is_unavailable(&err) || remaining == StdDuration::from_secs(0) {
+                    return Err(err).context(PgError);
+                }
+                let wait = delay.min(remaining);
+                eprintln!(
+                    "Could not connect to the database, trying again in {:?}: {}",
+                    wait, err
+                );
+                thread::sleep(wait);
+                delay = (delay * 2).min(MAX_STARTUP_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+pub fn init_db(config: &Config) -> Result<(), Error> {
+    // Migrations should not be cut short by the statement timeout
+    let conn = connect_on_startup(config)?;
 
     let trans = conn.transaction().context(PgError)?;
 
+    // Each distinct image is stored once in blobs, keyed by its sha256
     trans
-        .execute(
-            "CREATE TABLE IF NOT EXISTS timeline (
-            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW() PRIMARY KEY,
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS blobs (
+            hash BYTEA PRIMARY KEY,
             image_png BYTEA NOT NULL
-        )",
-            &[],
+        );
+        CREATE TABLE IF NOT EXISTS timeline (
+            id BIGSERIAL PRIMARY KEY,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            image_hash BYTEA NOT NULL REFERENCES blobs (hash)
+        );",
+        )
+        .context(PgError)?;
+
+    // The timeline used to be keyed by created_at, which made two saves in the same instant fail
+    trans
+        .batch_execute(
+            "DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'timeline' AND column_name = 'id'
+                ) THEN
+                    ALTER TABLE timeline DROP CONSTRAINT timeline_pkey;
+                    ALTER TABLE timeline ADD COLUMN id BIGSERIAL PRIMARY KEY;
+                END IF;
+            END $$;
+            CREATE INDEX IF NOT EXISTS timeline_created_at_idx ON timeline (created_at);",
+        )
+        .context(PgError)?;
+
+    // The timeline used to store the image of every entry itself
+    trans
+        .batch_execute(
+            "DO $$
+            BEGIN
+                IF EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'timeline' AND column_name = 'image_png'
+                ) THEN
+                    INSERT INTO blobs (hash, image_png)
+                        SELECT DISTINCT ON (sha256(image_png)) sha256(image_png), image_png
+                        FROM timeline
+                        ON CONFLICT DO NOTHING;
+                    ALTER TABLE timeline ADD COLUMN image_hash BYTEA REFERENCES blobs (hash);
+                    UPDATE timeline SET image_hash = sha256(image_png);
+                    ALTER TABLE timeline ALTER COLUMN image_hash SET NOT NULL;
+                    ALTER TABLE timeline DROP COLUMN image_png;
+                END IF;
+            END $$;
+            CREATE INDEX IF NOT EXISTS timeline_image_hash_idx ON timeline (image_hash);",
+        )
+        .context(PgError)?;
+
+    // Images can have a smaller version for views that show many of them
+    trans
+        .batch_execute("ALTER TABLE blobs ADD COLUMN IF NOT EXISTS thumbnail_png BYTEA;")
+        .context(PgError)?;
+
+    // Bit n is set when character n looks different than in the previous entry
+    trans
+        .batch_execute("ALTER TABLE timeline ADD COLUMN IF NOT EXISTS changed_characters SMALLINT;")
+        .context(PgError)?;
+
+    // The colors of the visible pixels as `#rrggbb`, for searching the history by color
+    trans
+        .batch_execute(
+            "ALTER TABLE timeline ADD COLUMN IF NOT EXISTS colors TEXT[];
+            CREATE INDEX IF NOT EXISTS timeline_colors_idx ON timeline USING GIN (colors);",
         )
         .context(PgError)?;
 
     trans.commit().context(PgError)?;
 
+    backfill_changed_characters(&conn)?;
+    backfill_colors(&conn)
+}
+
+/// Entries read at a time when filling in the changed characters
+const BACKFILL_BATCH_SIZE: i64 = 500;
+
+/// Fills in the changed characters of the entries stored before they were tracked. It is done
+/// outside of the migration transaction, as it can take a while on a long history.
+fn backfill_changed_characters(conn: &Connection) -> Result<(), Error> {
+    let missing = conn
+        .query(
+            "SELECT 1 FROM timeline WHERE changed_characters IS NULL LIMIT 1",
+            &[],
+        )
+        .context(PgError)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    println!("Finding the changed characters of the history");
+
+    // Ids start at 1, so this is before every entry
+    let mut cursor: (DateTime<Utc>, i64) = (Utc.timestamp(0, 0), 0);
+    let mut previous_png: Option<Vec<u8>> = None;
+    loop {
+        let rows = conn
+            .query(
+                "SELECT id, created_at, changed_characters, image_png
+                FROM timeline JOIN blobs ON hash = image_hash
+                WHERE (created_at, id) > ($1, $2)
+                ORDER BY created_at, id LIMIT $3",
+                &[&cursor.0, &cursor.1, &BACKFILL_BATCH_SIZE],
+            )
+            .context(PgError)?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        for row in &rows {
+            let id: i64 = row.get(0);
+            let changed: Option<i16> = row.get(2);
+            let logo_png: Vec<u8> = row.get(3);
+            if changed.is_none() {
+                let changed = changed_characters(previous_png.as_deref(), &logo_png);
+                conn.execute(
+                    "UPDATE timeline SET changed_characters = $1 WHERE id = $2",
+                    &[&changed, &id],
+                )
+                .context(PgError)?;
+            }
+            cursor = (row.get(1), id);
+            previous_png = Some(logo_png);
+        }
+    }
+}
+
+/// Fills in the colors of the entries stored before they were tracked, once per distinct image
+fn backfill_colors(conn: &Connection) -> Result<(), Error> {
+    let missing = conn
+        .query(
+            "SELECT DISTINCT image_hash FROM timeline WHERE colors IS NULL",
+            &[],
+        )
+        .context(PgError)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    println!("Finding the colors of the history");
+
+    for row in &missing {
+        let hash: Vec<u8> = row.get(0);
+        let blob = conn
+            .query("SELECT image_png FROM blobs WHERE hash = $1", &[&hash])
+            .context(PgError)?;
+        let logo_png: Vec<u8> = blob.get(0).get(0);
+        conn.execute(
+            "UPDATE timeline SET colors = $1 WHERE image_hash = $2 AND colors IS NULL",
+            &[&image_colors(&logo_png), &hash],
+        )
+        .context(PgError)?;
+    }
     Ok(())
 }
 
-pub fn save_logo(logo_png: &[u8]) -> Result<(), Error> {
-    let conn = get_conn()?;
+/// The colors of a logo, where one that can't be read has none
+fn image_colors(logo_png: &[u8]) -> Vec<String> {
+    logo::image_colors(logo_png).unwrap_or_else(|err| {
+        eprintln!("Error reading the colors of a logo: {}", err);
+        vec![]
+    })
+}
+
+/// The characters of a logo that changed from the image of the entry before it
+fn changed_characters(previous_png: Option<&[u8]>, logo_png: &[u8]) -> i16 {
+    match previous_png {
+        // Everything is new in the first entry
+        None => logo::ALL_CHARACTERS,
+        // The same pixels are always encoded the same way
+        Some(previous_png) if previous_png == logo_png => 0,
+        Some(previous_png) => {
+            logo::changed_characters(previous_png, logo_png).unwrap_or_else(|err| {
+                // Images that can't be compared count as changed everywhere
+                eprintln!("Error comparing logo to the previous one: {}", err);
+                logo::ALL_CHARACTERS
+            })
+        }
+    }
+}
+
+pub fn save_logo(
+    config: &Config,
+    logo_png: &[u8],
+    thumbnail_png: Option<&[u8]>,
+) -> Result<(), Error> {
+    let conn = get_conn(config)?;
 
     let trans = conn.transaction().context(PgError)?;
 
     trans
-        .execute("INSERT INTO timeline (image_png) VALUES ($1)", &[&logo_png])
+        .execute(
+            "INSERT INTO blobs (hash, image_png, thumbnail_png) VALUES (sha256($1), $1, $2)
+            ON CONFLICT (hash) DO UPDATE
+            SET thumbnail_png = COALESCE(blobs.thumbnail_png, EXCLUDED.thumbnail_png)",
+            &[&logo_png, &thumbnail_png],
+        )
+        .context(PgError)?;
+    let previous = trans
+        .query(
+            "SELECT image_png FROM timeline JOIN blobs ON hash = image_hash
+            ORDER BY created_at DESC, id DESC LIMIT 1",
+            &[],
+        )
+        .context(PgError)?;
+    let previous_png: Option<Vec<u8>> = previous.iter().next().map(|row| row.get(0));
+    let changed_characters = changed_characters(previous_png.as_deref(), logo_png);
+    trans
+        .execute(
+            "INSERT INTO timeline (image_hash, changed_characters, colors)
+            VALUES (sha256($1), $2, $3)",
+            &[&logo_png, &changed_characters, &image_colors(logo_png)],
+        )
         .context(PgError)?;
 
     trans.commit().context(PgError)?;
@@ -103,84 +508,1297 @@ pub fn save_logo(logo_png: &[u8]) -> Result<(), Error> {
     Ok(())
 }
 
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+}
+
+/// Where a page of the history starts, after the entry that ended the page before. It is written
+/// as the time and id of that entry, like `2019-09-01T12:00:00.000001Z_42`, which can be put in a
+/// query string as it is. A cursor of only a time starts after every entry at that time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cursor {
+    time: DateTime<Utc>,
+    id: Option<i64>,
+}
+
+impl Cursor {
+    fn after(time: DateTime<Utc>, id: i64) -> Self {
+        Cursor { time, id: Some(id) }
+    }
+
+    /// The `(created_at, id)` that the entries of the page come after in `order`
+    fn keyset(self, order: Order) -> (DateTime<Utc>, i64) {
+        let id = self.id.unwrap_or(match order {
+            Order::Asc => i64::MAX,
+            Order::Desc => i64::MIN,
+        });
+        (self.time, id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(cursor: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a valid cursor", cursor);
+        let (time, id) = match cursor.rsplitn(2, '_').collect::<Vec<_>>()[..] {
+            [id, time] => (time, Some(id.parse().map_err(|_| invalid())?)),
+            _ => (cursor, None),
+        };
+        Ok(Cursor {
+            time: time.parse().map_err(|_| invalid())?,
+            id,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.time.to_rfc3339_opts(SecondsFormat::Micros, true)
+        )?;
+        if let Some(id) = self.id {
+            write!(f, "_{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The condition for the entries after `keyset` in `order`, the `(created_at, id)` of a `Cursor`,
+/// adding its parameters to `params`
+fn cursor_condition<'a>(
+    keyset: &'a (DateTime<Utc>, i64),
+    order: Order,
+    params: &mut Vec<&'a dyn ToSql>,
+) -> String {
+    let condition = format!(
+        "(created_at, id) {} (${}, ${})",
+        match order {
+            Order::Asc => ">",
+            Order::Desc => "<",
+        },
+        params.len() + 1,
+        params.len() + 2
+    );
+    params.push(&keyset.0);
+    params.push(&keyset.1);
+    condition
+}
+
+/// Query parameters for the history api.
+///
+/// Pages can be fetched either with `offset`, or with `cursor` set to the `X-Next-Cursor` header
+/// of the previous page. The cursor is preferred as it stays fast on large histories.
 #[derive(Debug, Deserialize, Copy, Clone, Default)]
 pub struct GetHistoryOptions {
     limit: Option<u32>,
+    offset: Option<u32>,
+    #[serde(default)]
+    order: Order,
+    /// Only return entries after this one (before it when the order is `desc`)
+    cursor: Option<Cursor>,
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
+    /// Send the thumbnails, for the images that have one
+    #[serde(default)]
+    thumbnail: bool,
+    /// Only return entries where this character changed from the entry before
+    character: Option<u8>,
 }
 
-pub fn get_history(options: GetHistoryOptions) -> Result<reply::Response, Error> {
-    let mut query_str = "SELECT created_at, image_png FROM timeline ORDER BY created_at".to_owned();
-    if let Some(limit) = options.limit {
-        // NOTE: This is safe because we know that limit is a number
+/// The image column to read, preferring the thumbnail when it is asked for
+fn image_column(thumbnail: bool) -> &'static str {
+    if thumbnail {
+        "COALESCE(thumbnail_png, image_png)"
+    } else {
+        "image_png"
+    }
+}
+
+pub fn get_history(
+    config: &Config,
+    options: GetHistoryOptions,
+    budget: Budget,
+) -> Result<reply::Response, Error> {
+    // A max of 0 means that there is no max
+    let max_rows = Some(config.max_history_rows).filter(|&max_rows| max_rows > 0);
+    if let (Some(max_rows), Some(limit)) = (max_rows, options.limit) {
+        if limit > max_rows {
+            return too_many_rows(max_rows);
+        }
+    }
+
+    let mut query_str = format!(
+        "SELECT created_at, {}, changed_characters, id FROM timeline
+        JOIN blobs ON hash = image_hash",
+        image_column(options.thumbnail)
+    );
+    let mut conditions = vec![];
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let keyset = options.cursor.map(|cursor| cursor.keyset(options.order));
+    if let Some(ref keyset) = keyset {
+        conditions.push(cursor_condition(keyset, options.order, &mut params));
+    }
+    let character_mask;
+    if let Some(character) = options.character {
+        if i16::from(character) >= logo::CHARACTERS {
+            return Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(format!("character must be between 0 and {}", logo::CHARACTERS - 1).into())
+                .context(HttpError);
+        }
+        character_mask = 1i16 << character;
+        conditions.push(format!("changed_characters & ${} != 0", params.len() + 1));
+        params.push(&character_mask);
+    }
+    if !conditions.is_empty() {
+        query_str.push_str(" WHERE ");
+        query_str.push_str(&conditions.join(" AND "));
+    }
+    query_str.push_str(&format!(
+        " ORDER BY created_at {0}, id {0}",
+        options.order.as_sql()
+    ));
+    // Without a limit, fetch one row more than allowed to know if the history is too large
+    let limit = options
+        .limit
+        .or_else(|| max_rows.map(|max_rows| max_rows.saturating_add(1)));
+    // NOTE: This is safe because we know that limit and offset are numbers
+    if let Some(limit) = limit {
         query_str.push_str(&format!(" LIMIT {}", limit));
     }
+    if let Some(offset) = options.offset {
+        query_str.push_str(&format!(" OFFSET {}", offset));
+    }
 
-    let conn = get_conn()?;
-    let res = conn.query(&query_str, &[]).context(PgError)?;
+    let conn = get_conn_within(config, budget)?;
+    let res = query_within(&conn, budget, &query_str, &params)?;
+    if let Some(max_rows) = max_rows {
+        if res.len() > max_rows as usize {
+            return too_many_rows(max_rows);
+        }
+    }
 
+    let next_cursor = next_cursor(&res, options.limit);
     let data = res
         .into_iter()
         .map(|row| LogoState {
-            time: row.get(0),
+            time: Time {
+                time: row.get(0),
+                format: options.time_format,
+            },
             logo: row.get(1),
+            changed_characters: row.get(2),
         })
         .collect::<Vec<_>>();
 
     // TODO: Check if the browser accept gzip
     // let result = serde_json::to_vec(&data).context(JsonError)?;
 
+    // Written an entry at a time, as encoding a long history is most of the work
     let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
-    serde_json::to_writer(&mut encoder, &data).context(JsonError)?;
+    encoder.write_all(b"[").context(EncodeError)?;
+    for (index, entry) in data.iter().enumerate() {
+        budget.check().context(OverBudget)?;
+        if index > 0 {
+            encoder.write_all(b",").context(EncodeError)?;
+        }
+        options
+            .naming
+            .to_writer(&mut encoder, entry)
+            .context(JsonError)?;
+    }
+    encoder.write_all(b"]").context(EncodeError)?;
 
     let result = encoder.finish().context(EncodeError)?;
 
-    Ok(Response::builder()
+    let mut response = Response::builder();
+    response
+        .header("Content-Type", "application/json")
+        .header("Content-Encoding", "gzip");
+    if let Some(next_cursor) = next_cursor {
+        response.header("X-Next-Cursor", next_cursor.to_string());
+    }
+
+    response.body(result.into()).context(HttpError)
+}
+
+/// The cursor of the page after `rows`, which have the `created_at` and `id` of the entries. A
+/// full page means there might be more entries after it.
+fn next_cursor(rows: &Rows, limit: Option<u32>) -> Option<Cursor> {
+    match limit {
+        Some(limit) if limit > 0 && rows.len() == limit as usize => {
+            let last = rows.get(rows.len() - 1);
+            Some(Cursor::after(last.get("created_at"), last.get("id")))
+        }
+        _ => None,
+    }
+}
+
+fn too_many_rows(max_rows: u32) -> Result<reply::Response, Error> {
+    Response::builder()
+        .status(http::StatusCode::PAYLOAD_TOO_LARGE)
+        .body(
+            format!(
+                "At most {} entries can be exported at once. Set a limit, and use the cursor to page through the history.",
+                max_rows
+            )
+            .into(),
+        )
+        .context(HttpError)
+}
+
+/// The times matched by a timestamp given with the precision it is written in, so that
+/// `12:00:01Z` matches everything stored during that second, and `12:00:01.5Z` everything
+/// during that tenth of a second
+fn precision_window(date: &str) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+    let start: DateTime<Utc> = date.parse().context(ParseDateError)?;
+
+    let fraction_digits = date
+        .find('.')
+        .map(|dot| {
+            date[dot + 1..]
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .count()
+        })
+        .unwrap_or(0);
+    let nanoseconds = 10i64.pow(9u32.saturating_sub(fraction_digits as u32));
+    // Postgres stores microseconds, so a window can't be any smaller than that
+    let window = Duration::nanoseconds(nanoseconds.max(1_000));
+
+    Ok((start, start + window))
+}
+
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+pub struct HistoryEntryOptions {
+    /// Send the thumbnail, if the image has one
+    #[serde(default)]
+    thumbnail: bool,
+    /// Write the time of the entry in a strip below the image
+    #[serde(default)]
+    stamp: bool,
+}
+
+pub fn get_history_from_date(
+    config: &Config,
+    index: String,
+    options: HistoryEntryOptions,
+    if_none_match: Option<String>,
+) -> Result<reply::Response, Error> {
+    let (start, end) = precision_window(&index)?;
+
+    let conn = get_conn(config)?;
+    let res = query_history(
+        &conn,
+        &format!(
+            "SELECT {0}, encode(sha256({0}), 'hex'), created_at
+            FROM timeline JOIN blobs ON hash = image_hash
+            WHERE created_at >= $1 AND created_at < $2
+            ORDER BY created_at DESC, id DESC LIMIT 1",
+            image_column(options.thumbnail)
+        ),
+        &[&start, &end],
+    )?;
+
+    if res.is_empty() {
+        return Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body("No logo stored at that time".into())
+            .context(HttpError);
+    }
+
+    // A stored image never changes, so its hash tells the versions apart. A stamp also depends on
+    // the time, which may differ between entries with the same image.
+    let hash: String = res.get(0).get(1);
+    let time: DateTime<Utc> = res.get(0).get(2);
+    let etag = if options.stamp {
+        format!("\"{}-{}\"", hash, time.timestamp_nanos())
+    } else {
+        format!("\"{}\"", hash)
+    };
+    if let Some(if_none_match) = if_none_match {
+        if etag_matches(&if_none_match, &etag) {
+            return Response::builder()
+                .status(http::StatusCode::NOT_MODIFIED)
+                .header("ETag", etag)
+                .body(Default::default())
+                .context(HttpError);
+        }
+    }
+
+    let mut data: Vec<u8> = res.get(0).get(0);
+    if options.stamp {
+        let text = time.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+        data = match logo::stamp_png(&data, &text, config.png_srgb) {
+            Ok(stamped) => stamped,
+            Err(err) => {
+                eprintln!("Error stamping the logo: {}", err);
+                return Response::builder()
+                    .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body("Could not stamp the logo".into())
+                    .context(HttpError);
+            }
+        };
+    }
+
+    Response::builder()
+        .header("Content-Type", "image/png")
+        .header("ETag", etag)
+        .body(data.into())
+        .context(HttpError)
+}
+
+/// Query parameters for the delta of the live logo
+#[derive(Debug, Deserialize, Copy, Clone)]
+pub struct DeltaOptions {
+    /// Compare with the entry that was the logo at this time
+    since: DateTime<Utc>,
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
+}
+
+#[derive(Serialize)]
+struct LogoDelta {
+    /// When the entry that the live logo is compared with was stored
+    time: Time,
+    #[serde(flatten)]
+    delta: logo::Delta,
+}
+
+/// How the live logo differs from what it was at a time
+pub fn get_logo_delta(config: &Config, options: DeltaOptions) -> Result<reply::Response, Error> {
+    let (time, old_png) = match get_entry_at(config, options.since)? {
+        Some(entry) => entry,
+        None => return no_entry_by_then(),
+    };
+    let delta = match logo::get_logo_delta(config, &old_png) {
+        Ok(delta) => delta,
+        Err(err) => {
+            eprintln!("Error comparing the live logo: {}", err);
+            return Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not compare the logos".into())
+                .context(HttpError);
+        }
+    };
+    let result = options
+        .naming
+        .to_vec(&LogoDelta {
+            time: Time {
+                time,
+                format: options.time_format,
+            },
+            delta,
+        })
+        .context(JsonError)?;
+
+    Response::builder()
         .header("Content-Type", "application/json")
-        .header("Content-Encoding", "gzip")
         .body(result.into())
-        .context(HttpError)?)
+        .context(HttpError)
+}
+
+/// When an entry was stored, and its PNG
+type Entry = (DateTime<Utc>, Vec<u8>);
+
+/// The entry that was the logo at `since`
+fn get_entry_at(config: &Config, since: DateTime<Utc>) -> Result<Option<Entry>, Error> {
+    let conn = get_conn(config)?;
+    let res = query_history(
+        &conn,
+        "SELECT created_at, image_png FROM timeline JOIN blobs ON hash = image_hash
+        WHERE created_at <= $1
+        ORDER BY created_at DESC, id DESC LIMIT 1",
+        &[&since],
+    )?;
+    Ok(res.iter().next().map(|row| (row.get(0), row.get(1))))
+}
+
+fn no_entry_by_then() -> Result<reply::Response, Error> {
+    Response::builder()
+        .status(http::StatusCode::NOT_FOUND)
+        .body("No logo was stored by then".into())
+        .context(HttpError)
+}
+
+/// Longest transition that can be requested, in milliseconds
+const MAX_TRANSITION_DURATION: u32 = 60_000;
+
+/// Query parameters for the transition to the live logo
+#[derive(Debug, Deserialize, Copy, Clone)]
+pub struct TransitionOptions {
+    /// Animate from the entry that was the logo at this time
+    since: DateTime<Utc>,
+    /// Length of the animation in milliseconds
+    #[serde(default = "default_transition_duration")]
+    duration: u32,
+}
+
+fn default_transition_duration() -> u32 {
+    1000
+}
+
+/// A Lottie animation of the pixels that changed from what the logo was at a time to the live
+/// logo
+pub fn get_logo_transition(
+    config: &Config,
+    options: TransitionOptions,
+) -> Result<reply::Response, Error> {
+    if options.duration == 0 || options.duration > MAX_TRANSITION_DURATION {
+        return Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .body(format!("duration must be between 1 and {}", MAX_TRANSITION_DURATION).into())
+            .context(HttpError);
+    }
+    let (_, old_png) = match get_entry_at(config, options.since)? {
+        Some(entry) => entry,
+        None => return no_entry_by_then(),
+    };
+    let transition = match logo::get_logo_transition(config, &old_png, options.duration) {
+        Ok(transition) => transition,
+        Err(err) => {
+            eprintln!("Error animating the live logo: {}", err);
+            return Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not animate the logos".into())
+                .context(HttpError);
+        }
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&transition).context(JsonError)?.into())
+        .context(HttpError)
+}
+
+/// Whether an `If-None-Match` header lists an ETag, comparing them weakly like it should
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|listed| listed.trim().trim_start_matches("W/") == etag)
 }
 
-pub fn get_history_from_date(index: String) -> Result<reply::Response, Error> {
-    let date: DateTime<Utc> = index.parse().context(ParseDateError)?;
+/// A stored version of the logo
+pub struct Frame {
+    pub id: i64,
+    pub time: DateTime<Utc>,
+    pub png: Vec<u8>,
+}
 
-    let conn = get_conn()?;
+/// The oldest `limit` entries after `(time, id)`, for walking through the whole history
+pub fn get_frames_after(
+    config: &Config,
+    (time, id): (DateTime<Utc>, i64),
+    limit: i64,
+) -> Result<Vec<Frame>, Error> {
+    let conn = get_conn(config)?;
     let res = conn
         .query(
-            "SELECT image_png FROM timeline WHERE created_at=$1",
-            &[&date],
+            "SELECT id, created_at, image_png FROM timeline JOIN blobs ON hash = image_hash
+            WHERE (created_at, id) > ($1, $2)
+            ORDER BY created_at, id LIMIT $3",
+            &[&time, &id, &limit],
         )
         .context(PgError)?;
 
-    let data: Vec<u8> = res.get(0).get(0);
+    Ok(res
+        .into_iter()
+        .map(|row| Frame {
+            id: row.get(0),
+            time: row.get(1),
+            png: row.get(2),
+        })
+        .collect())
+}
 
-    Ok(Response::builder()
-        .header("Content-Type", "image/png")
-        .body(data.into())
-        .context(HttpError)?)
+/// Entries read from the database at a time while exporting the history
+const EXPORT_BATCH: usize = 100;
+
+/// Query parameters for the NDJSON export of the history
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+pub struct ExportOptions {
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
 }
 
 #[derive(Serialize)]
-pub struct HistoryIndex {
-    time: DateTime<Utc>,
+struct ExportEntry {
+    time: Time,
+    png_base64: String,
 }
 
-pub fn get_history_index() -> Result<reply::Response, Error> {
-    let conn = get_conn()?;
+/// Writes the lines of the export, gzipping them if asked to, and hands them out a batch at a time
+struct ExportWriter {
+    options: ExportOptions,
+    gzip: Option<GzEncoder<Vec<u8>>>,
+    plain: Vec<u8>,
+}
 
-    let res = conn
-        .query("SELECT created_at FROM timeline ORDER BY created_at", &[])
+impl ExportWriter {
+    fn write(&mut self, frames: &[Frame]) -> Result<Vec<u8>, Error> {
+        for frame in frames {
+            let entry = ExportEntry {
+                time: Time {
+                    time: frame.time,
+                    format: self.options.time_format,
+                },
+                png_base64: base64::encode(&frame.png),
+            };
+            let out: &mut dyn Write = match self.gzip {
+                Some(ref mut encoder) => encoder,
+                None => &mut self.plain,
+            };
+            self.options
+                .naming
+                .to_writer(&mut *out, &entry)
+                .context(JsonError)?;
+            out.write_all(b"\n").context(EncodeError)?;
+        }
+        match self.gzip {
+            // Flushing ends the compressed block, so the client can read the lines so far
+            Some(ref mut encoder) => {
+                encoder.flush().context(EncodeError)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            None => Ok(std::mem::take(&mut self.plain)),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, Error> {
+        match self.gzip {
+            Some(encoder) => encoder.finish().context(EncodeError),
+            None => Ok(self.plain),
+        }
+    }
+}
+
+/// Exports that are running, bounded by `max_exports`
+static EXPORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `max_exports` exports that can run at once, given back when it is dropped
+struct ExportSlot;
+
+impl ExportSlot {
+    fn take(max_exports: usize) -> Option<Self> {
+        EXPORTS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |exports| {
+                Some(exports + 1).filter(|&exports| exports <= max_exports)
+            })
+            .ok()
+            .map(|_| ExportSlot)
+    }
+}
+
+impl Drop for ExportSlot {
+    fn drop(&mut self) {
+        EXPORTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads the whole history, oldest first, in one transaction through a portal that fetches
+/// `EXPORT_BATCH` entries at a time, so the export sees the history as it was when it started.
+/// `on_batch` gets each batch, and stops the export by returning false.
+fn export_batches(
+    config: &Config,
+    mut on_batch: impl FnMut(&[Frame]) -> Result<bool, Error>,
+) -> Result<(), Error> {
+    let conn = get_conn(config)?;
+    let trans = conn.transaction().context(PgError)?;
+    let stmt = trans
+        .prepare(
+            "SELECT id, created_at, image_png FROM timeline JOIN blobs ON hash = image_hash
+            ORDER BY created_at, id",
+        )
+        .context(PgError)?;
+    let mut rows = stmt
+        .lazy_query(&trans, &[], EXPORT_BATCH as i32)
         .context(PgError)?;
 
+    let mut batch = Vec::with_capacity(EXPORT_BATCH);
+    while let Some(row) = rows.next().context(PgError)? {
+        batch.push(Frame {
+            id: row.get(0),
+            time: row.get(1),
+            png: row.get(2),
+        });
+        if batch.len() == EXPORT_BATCH {
+            if !on_batch(&batch)? {
+                return Ok(());
+            }
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        on_batch(&batch)?;
+    }
+    Ok(())
+}
+
+/// A channel to the body of the export, which hands each chunk over once the client has taken
+/// the one before it
+type ExportSender = Option<mpsc::Sender<Result<Vec<u8>, String>>>;
+
+/// Sends a chunk of the export, telling if the client is still there
+fn send_chunk(tx: &mut ExportSender, chunk: Result<Vec<u8>, String>) -> bool {
+    match tx.take().map(|sender| sender.send(chunk).wait()) {
+        Some(Ok(sender)) => {
+            *tx = Some(sender);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Streams the whole history, oldest first, as one JSON object per line. It is read on a thread
+/// of its own with a single connection, waiting for the client to take each batch before reading
+/// the next, so the export takes the same memory however long the history is. At most
+/// `max_exports` run at once, and the others are answered with 503.
+pub fn export_history(
+    config: Arc<Config>,
+    options: ExportOptions,
+    gzip: bool,
+) -> Result<reply::Response, Error> {
+    let slot = match ExportSlot::take(config.max_exports) {
+        Some(slot) => slot,
+        None => {
+            return Response::builder()
+                .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                .header("Retry-After", "60")
+                .body("Too many exports are running, try again later".into())
+                .context(HttpError)
+        }
+    };
+    let mut writer = ExportWriter {
+        options,
+        gzip: Some(GzEncoder::new(Vec::new(), Compression::fast())).filter(|_| gzip),
+        plain: vec![],
+    };
+
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, String>>(0);
+    // The first batch is read before answering, so a database that is down gets an error status
+    let (started_tx, started_rx) = sync_channel::<Result<(), Error>>(1);
+    thread::spawn(move || {
+        let _slot = slot;
+        let mut started = Some(started_tx);
+        let mut tx = Some(tx);
+        let result = export_batches(&config, |frames| {
+            if let Some(started) = started.take() {
+                let _ = started.send(Ok(()));
+            }
+            let chunk = writer.write(frames)?;
+            Ok(send_chunk(&mut tx, Ok(chunk)))
+        });
+        match (result, started) {
+            (Err(err), Some(started)) => {
+                let _ = started.send(Err(err));
+            }
+            (Err(err), None) => {
+                eprintln!("Error exporting the history: {}", err);
+                send_chunk(&mut tx, Err(err.to_string()));
+            }
+            (Ok(()), started) => {
+                if let Some(started) = started {
+                    let _ = started.send(Ok(()));
+                }
+                send_chunk(&mut tx, writer.finish().map_err(|err| err.to_string()));
+            }
+        }
+    });
+    // The thread only ends without a word if it panicked, which the body then ends early for
+    if let Ok(Err(err)) = started_rx.recv() {
+        return Err(err);
+    }
+
+    // An error ends the body early, so the client can tell that the export is incomplete
+    let body = rx.then(|chunk| match chunk {
+        Ok(Ok(chunk)) => Ok(chunk),
+        Ok(Err(err)) => Err(io::Error::other(err)),
+        Err(()) => Err(io::Error::other("The export ended")),
+    });
+    let mut response = Response::builder();
+    response.header("Content-Type", "application/x-ndjson");
+    if gzip {
+        response.header("Content-Encoding", "gzip");
+    }
+    response.body(Body::wrap_stream(body)).context(HttpError)
+}
+
+/// Entries shown on each page of the html history
+const HISTORY_PAGE_SIZE: u32 = 50;
+
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+pub struct HistoryPageOptions {
+    /// Page to show, counting from 1 with the newest entries first
+    page: Option<u32>,
+}
+
+pub fn get_history_page(
+    config: &Config,
+    options: HistoryPageOptions,
+) -> Result<reply::Response, Error> {
+    let page = options.page.unwrap_or(1).max(1);
+
+    let conn = get_conn(config)?;
+    // Fetch one entry more than is shown to know if there is a next page
+    // NOTE: This is safe because we know that the limit and offset are numbers
+    let res = query_history(
+        &conn,
+        &format!(
+            "SELECT created_at FROM timeline ORDER BY created_at DESC, id DESC LIMIT {} OFFSET {}",
+            HISTORY_PAGE_SIZE + 1,
+            u64::from(page - 1) * u64::from(HISTORY_PAGE_SIZE)
+        ),
+        &[],
+    )?;
+
+    let times = res
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect::<Vec<DateTime<Utc>>>();
+
+    let entries = times
+        .iter()
+        .take(HISTORY_PAGE_SIZE as usize)
+        .map(|time| {
+            let time = time.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+            format!(
+                "            <li><a href=\"/api/v1/history/{0}\"><img src=\"/api/v1/history/{0}?thumbnail=true\" alt=\"Logo at {0}\" /></a>{0}</li>",
+                time
+            )
+        })
+        .collect::<Vec<_>>();
+    let entries = if entries.is_empty() {
+        "            <li>No logos stored here</li>".to_owned()
+    } else {
+        entries.join("\n")
+    };
+
+    let previous = if page > 1 {
+        format!("<a href=\"/history.html?page={}\">Newer</a>", page - 1)
+    } else {
+        "<span></span>".to_owned()
+    };
+    let next = if times.len() > HISTORY_PAGE_SIZE as usize {
+        format!("<a href=\"/history.html?page={}\">Older</a>", page + 1)
+    } else {
+        "<span></span>".to_owned()
+    };
+
+    let html = include_str!("history_page.html")
+        .replace("{page}", &page.to_string())
+        .replace("{entries}", &entries)
+        .replace("{previous}", &previous)
+        .replace("{next}", &next);
+
+    Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(html.into())
+        .context(HttpError)
+}
+
+#[derive(Serialize)]
+pub struct HistoryIndex {
+    time: Time,
+    changed_characters: Option<i16>,
+}
+
+#[derive(Serialize)]
+pub struct FirstSeen {
+    time: Time,
+}
+
+/// Query parameters for the history index, where `order=desc&limit=N` gives the newest N entries
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+pub struct GetHistoryIndexOptions {
+    limit: Option<u32>,
+    #[serde(default)]
+    order: Order,
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
+}
+
+pub fn get_history_index(
+    config: &Config,
+    options: GetHistoryIndexOptions,
+    if_none_match: Option<String>,
+) -> Result<reply::Response, Error> {
+    let conn = get_conn(config)?;
+    // The index only changes when an entry is stored, or when old ones are removed. This is read
+    // before the index, so an entry stored in between gives a new ETag on the next request.
+    let res = query_history(&conn, "SELECT count(*), max(created_at) FROM timeline", &[])?;
+    let count: i64 = res.get(0).get(0);
+    let latest: Option<DateTime<Utc>> = res.get(0).get(1);
+    let etag = format!(
+        "\"{}-{}\"",
+        count,
+        latest.map_or(0, |latest| latest.timestamp_nanos())
+    );
+    if let Some(if_none_match) = if_none_match {
+        if etag_matches(&if_none_match, &etag) {
+            return Response::builder()
+                .status(http::StatusCode::NOT_MODIFIED)
+                .header("ETag", etag)
+                .body(Default::default())
+                .context(HttpError);
+        }
+    }
+
+    let mut query_str = format!(
+        "SELECT created_at, changed_characters FROM timeline ORDER BY created_at {0}, id {0}",
+        options.order.as_sql()
+    );
+    // NOTE: This is safe because we know that limit is a number
+    if let Some(limit) = options.limit {
+        query_str.push_str(&format!(" LIMIT {}", limit));
+    }
+
+    let res = query_history(&conn, &query_str, &[])?;
+
     let data = res
         .into_iter()
-        .map(|row| HistoryIndex { time: row.get(0) })
+        .map(|row| HistoryIndex {
+            time: Time {
+                time: row.get(0),
+                format: options.time_format,
+            },
+            changed_characters: row.get(1),
+        })
         .collect::<Vec<_>>();
 
-    let result = serde_json::to_string(&data).context(JsonError)?;
+    let result = options.naming.to_vec(&data).context(JsonError)?;
 
     Ok(Response::builder()
         .header("Content-Type", "application/json")
+        .header("ETag", etag)
         .body(result.into())
         .context(HttpError)?)
 }
+
+/// Most samples of the history that can be asked for at once
+const MAX_SAMPLES: u32 = 1000;
+
+/// Query parameters for samples of the history
+#[derive(Debug, Deserialize, Copy, Clone)]
+pub struct SampleHistoryOptions {
+    /// Times to sample, spread evenly from the first entry to the last
+    #[serde(default = "default_samples")]
+    n: u32,
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
+}
+
+fn default_samples() -> u32 {
+    50
+}
+
+#[derive(Serialize)]
+struct HistorySample {
+    time: Time,
+    /// The thumbnail, or the image if it has none
+    #[serde(serialize_with = "as_base64")]
+    thumbnail: Vec<u8>,
+}
+
+/// The entries that were the logo at `n` times spread evenly over the whole history, oldest first,
+/// for a preview of it. An entry that was the logo at several of the times is only sent once.
+pub fn sample_history(
+    config: &Config,
+    options: SampleHistoryOptions,
+) -> Result<reply::Response, Error> {
+    if options.n == 0 || options.n > MAX_SAMPLES {
+        return Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .body(format!("n must be between 1 and {}", MAX_SAMPLES).into())
+            .context(HttpError);
+    }
+
+    let conn = get_conn(config)?;
+    let res = query_history(
+        &conn,
+        "WITH bounds AS (SELECT min(created_at) AS first, max(created_at) AS last FROM timeline)
+        SELECT created_at, thumbnail FROM (
+            SELECT DISTINCT ON (entry.id) entry.id, entry.created_at, entry.thumbnail
+            FROM bounds, generate_series(0, $1::int - 1) AS sample,
+            LATERAL (
+                SELECT id, created_at, COALESCE(thumbnail_png, image_png) AS thumbnail
+                FROM timeline JOIN blobs ON hash = image_hash
+                WHERE created_at <= bounds.first
+                    + (bounds.last - bounds.first) * sample / GREATEST($1::int - 1, 1)
+                ORDER BY created_at DESC, id DESC LIMIT 1
+            ) entry
+            ORDER BY entry.id
+        ) samples
+        ORDER BY created_at, id",
+        &[&(options.n as i32)],
+    )?;
+
+    let data = res
+        .into_iter()
+        .map(|row| HistorySample {
+            time: Time {
+                time: row.get(0),
+                format: options.time_format,
+            },
+            thumbnail: row.get(1),
+        })
+        .collect::<Vec<_>>();
+
+    let result = options.naming.to_vec(&data).context(JsonError)?;
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(result.into())
+        .context(HttpError)
+}
+
+/// Entries returned by a search when no limit is given
+const SEARCH_PAGE_SIZE: u32 = 100;
+
+/// Query parameters for searching the history, paged like the history api
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SearchHistoryOptions {
+    /// Only return entries with a pixel of this color. It is parsed when searching, as a query
+    /// that doesn't parse would be taken for a date.
+    color: Option<String>,
+    limit: Option<u32>,
+    #[serde(default)]
+    order: Order,
+    /// Only return entries after this one (before it when the order is `desc`)
+    cursor: Option<Cursor>,
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
+}
+
+/// The entries where the logo has a color
+pub fn search_history(
+    config: &Config,
+    options: SearchHistoryOptions,
+    budget: Budget,
+) -> Result<reply::Response, Error> {
+    let limit = options.limit.unwrap_or(SEARCH_PAGE_SIZE);
+    if config.max_history_rows > 0 && limit > config.max_history_rows {
+        return too_many_rows(config.max_history_rows);
+    }
+    let color = match options.color.as_deref().map(str::parse::<Color>) {
+        Some(Ok(color)) => color.opaque().to_string(),
+        Some(Err(err)) => {
+            return Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body(err.into())
+                .context(HttpError)
+        }
+        None => {
+            return Response::builder()
+                .status(http::StatusCode::BAD_REQUEST)
+                .body("A color to search for is needed".into())
+                .context(HttpError)
+        }
+    };
+
+    let mut query_str = "SELECT created_at, changed_characters, id FROM timeline
+        WHERE colors @> ARRAY[$1]"
+        .to_owned();
+    let mut params: Vec<&dyn ToSql> = vec![&color];
+    let keyset = options.cursor.map(|cursor| cursor.keyset(options.order));
+    if let Some(ref keyset) = keyset {
+        query_str.push_str(" AND ");
+        query_str.push_str(&cursor_condition(keyset, options.order, &mut params));
+    }
+    // NOTE: This is safe because we know that limit is a number
+    query_str.push_str(&format!(
+        " ORDER BY created_at {0}, id {0} LIMIT {1}",
+        options.order.as_sql(),
+        limit
+    ));
+    let conn = get_conn_within(config, budget)?;
+    let res = query_within(&conn, budget, &query_str, &params)?;
+    let next_cursor = next_cursor(&res, Some(limit));
+
+    let data = res
+        .into_iter()
+        .map(|row| HistoryIndex {
+            time: Time {
+                time: row.get(0),
+                format: options.time_format,
+            },
+            changed_characters: row.get(1),
+        })
+        .collect::<Vec<_>>();
+
+    let result = options.naming.to_vec(&data).context(JsonError)?;
+
+    let mut response = Response::builder();
+    response.header("Content-Type", "application/json");
+    if let Some(next_cursor) = next_cursor {
+        response.header("X-Next-Cursor", next_cursor.to_string());
+    }
+
+    response.body(result.into()).context(HttpError)
+}
+
+#[derive(Debug, Deserialize, Copy, Clone, Default)]
+pub struct FirstSeenOptions {
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    naming: Naming,
+}
+
+/// When the image with a sha256 (in hex) was first stored
+pub fn get_first_seen(
+    config: &Config,
+    hash: String,
+    options: FirstSeenOptions,
+) -> Result<reply::Response, Error> {
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .body("The hash must be a sha256 written as 64 hex digits".into())
+            .context(HttpError);
+    }
+
+    let conn = get_conn(config)?;
+    let res = query_history(
+        &conn,
+        "SELECT MIN(created_at) FROM timeline WHERE image_hash = decode($1, 'hex')",
+        &[&hash],
+    )?;
+
+    let first_seen: Option<DateTime<Utc>> = res.get(0).get(0);
+    let time = match first_seen {
+        Some(time) => time,
+        None => {
+            return Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body("No logo with that hash has been stored".into())
+                .context(HttpError)
+        }
+    };
+
+    let result = options
+        .naming
+        .to_vec(&FirstSeen {
+            time: Time {
+                time,
+                format: options.time_format,
+            },
+        })
+        .context(JsonError)?;
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(result.into())
+        .context(HttpError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(date: &str) -> (String, String) {
+        let (start, end) = precision_window(date).unwrap();
+        (
+            start.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            end.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        )
+    }
+
+    #[test]
+    fn names_fields_in_camel_case() {
+        let index = vec![HistoryIndex {
+            time: Time {
+                time: Utc.timestamp_millis(1_567_339_200_000),
+                format: TimeFormat::EpochMs,
+            },
+            changed_characters: Some(3),
+        }];
+        assert_eq!(
+            String::from_utf8(Naming::Camel.to_vec(&index).unwrap()).unwrap(),
+            r#"[{"changedCharacters":3,"time":1567339200000}]"#
+        );
+        assert_eq!(
+            String::from_utf8(Naming::Snake.to_vec(&index).unwrap()).unwrap(),
+            r#"[{"time":1567339200000,"changed_characters":3}]"#
+        );
+    }
+
+    #[test]
+    fn matches_the_whole_second_of_truncated_timestamps() {
+        assert_eq!(
+            window("2019-09-01T12:00:01Z"),
+            (
+                "2019-09-01T12:00:01Z".to_owned(),
+                "2019-09-01T12:00:02Z".to_owned()
+            )
+        );
+        assert_eq!(
+            window("2019-09-01T14:00:59+02:00"),
+            (
+                "2019-09-01T12:00:59Z".to_owned(),
+                "2019-09-01T12:01:00Z".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn matches_fractions_of_a_second() {
+        assert_eq!(
+            window("2019-09-01T12:00:01.123Z"),
+            (
+                "2019-09-01T12:00:01.123Z".to_owned(),
+                "2019-09-01T12:00:01.124Z".to_owned()
+            )
+        );
+        assert_eq!(
+            window("2019-09-01T12:00:01.5Z"),
+            (
+                "2019-09-01T12:00:01.500Z".to_owned(),
+                "2019-09-01T12:00:01.600Z".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn matches_at_least_a_microsecond() {
+        assert_eq!(
+            window("2019-09-01T12:00:01.123456Z"),
+            (
+                "2019-09-01T12:00:01.123456Z".to_owned(),
+                "2019-09-01T12:00:01.123457Z".to_owned()
+            )
+        );
+        assert_eq!(
+            window("2019-09-01T12:00:01.123456789Z"),
+            (
+                "2019-09-01T12:00:01.123456789Z".to_owned(),
+                "2019-09-01T12:00:01.123457789Z".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn writes_cursors_that_survive_a_query_string() {
+        let time = Utc.timestamp_millis(1_567_339_200_001);
+        let cursor = Cursor::after(time, 42);
+        assert_eq!(cursor.to_string(), "2019-09-01T12:00:00.001000Z_42");
+        assert_eq!(cursor.to_string().parse(), Ok(cursor));
+        assert_eq!(cursor.keyset(Order::Desc), (time, 42));
+
+        // A time alone is still a cursor, which starts after every entry at that time
+        let cursor: Cursor = "2019-09-01T14:00:00.001+02:00".parse().unwrap();
+        assert_eq!(cursor.keyset(Order::Asc), (time, i64::MAX));
+        assert_eq!(cursor.keyset(Order::Desc), (time, i64::MIN));
+
+        assert!("2019-09-01T12:00:00Z_x".parse::<Cursor>().is_err());
+        assert!("42".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn bounds_the_exports_that_run_at_once() {
+        let first = ExportSlot::take(2).unwrap();
+        let second = ExportSlot::take(2).unwrap();
+        assert!(ExportSlot::take(2).is_none());
+        drop(first);
+        let third = ExportSlot::take(2).unwrap();
+        assert!(ExportSlot::take(2).is_none());
+        drop((second, third));
+        assert!(ExportSlot::take(0).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        assert!(precision_window("yesterday").is_err());
+    }
+
+    /// Needs a database it may empty at `TEST_DATABASE_URL`, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn migrates_the_first_schema() {
+        let config = Config {
+            database_url: std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set"),
+            ..Config::default()
+        };
+        let conn = connect(&config).unwrap();
+        conn.batch_execute(
+            "DROP TABLE IF EXISTS timeline, blobs;
+            CREATE TABLE timeline (
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW() PRIMARY KEY,
+                image_png BYTEA NOT NULL
+            );",
+        )
+        .unwrap();
+        let logo_png = config.error_image_data.as_slice();
+        conn.execute(
+            "INSERT INTO timeline (created_at, image_png)
+            VALUES ('2019-09-01T12:00:00Z', $1), ('2019-09-01T12:00:01Z', $1)",
+            &[&logo_png],
+        )
+        .unwrap();
+
+        init_db(&config).unwrap();
+        // Migrating again changes nothing
+        init_db(&config).unwrap();
+
+        let blobs = conn.query("SELECT image_png FROM blobs", &[]).unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs.get(0).get::<_, Vec<u8>>(0), logo_png);
+        let entries = conn
+            .query(
+                "SELECT id, changed_characters, colors IS NOT NULL FROM timeline ORDER BY created_at",
+                &[],
+            )
+            .unwrap();
+        let entries = entries
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect::<Vec<(i64, Option<i16>, bool)>>();
+        assert_eq!(
+            entries,
+            vec![(1, Some(logo::ALL_CHARACTERS), true), (2, Some(0), true)]
+        );
+        let indexes = conn
+            .query(
+                "SELECT 1 FROM pg_indexes WHERE indexname = 'timeline_image_hash_idx'",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(indexes.len(), 1);
+    }
+}
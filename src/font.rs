@@ -0,0 +1,94 @@
+/// Width of each glyph in pixels, before the column between glyphs
+const GLYPH_WIDTH: usize = 3;
+/// Height of each glyph in pixels
+pub const GLYPH_HEIGHT: usize = 5;
+
+/// The rows of a glyph top to bottom, three bits each with the leftmost pixel in the highest bit.
+/// Only what timestamps are written with has a glyph, and anything else is left blank.
+fn glyph(character: char) -> u16 {
+    match character {
+        '0' => 0b111_101_101_101_111,
+        '1' => 0b010_110_010_010_111,
+        '2' => 0b111_001_111_100_111,
+        '3' => 0b111_001_111_001_111,
+        '4' => 0b101_101_111_001_001,
+        '5' => 0b111_100_111_001_111,
+        '6' => 0b111_100_111_101_111,
+        '7' => 0b111_001_001_001_001,
+        '8' => 0b111_101_111_101_111,
+        '9' => 0b111_101_111_001_111,
+        '-' => 0b000_000_111_000_000,
+        ':' => 0b000_010_000_010_000,
+        'C' => 0b111_100_100_100_111,
+        'T' => 0b111_010_010_010_010,
+        'U' => 0b101_101_101_101_111,
+        _ => 0,
+    }
+}
+
+/// Width of a line of text drawn with `scale` pixels per font pixel
+pub fn text_width(text: &str, scale: usize) -> usize {
+    let characters = text.chars().count();
+    (characters * (GLYPH_WIDTH + 1)).saturating_sub(1) * scale
+}
+
+/// Draws a line of text into an RGBA image `width` pixels wide, with its top left corner at
+/// `origin`. Pixels that would be outside of the image are left out.
+pub fn draw_text(
+    image: &mut [u8],
+    width: usize,
+    origin: (usize, usize),
+    text: &str,
+    scale: usize,
+    color: [u8; 4],
+) {
+    let height = image.len() / 4 / width;
+    for (index, character) in text.chars().enumerate() {
+        let glyph = glyph(character);
+        let left = origin.0 + index * (GLYPH_WIDTH + 1) * scale;
+        for row in 0..GLYPH_HEIGHT {
+            for column in 0..GLYPH_WIDTH {
+                let bit = (GLYPH_HEIGHT - 1 - row) * GLYPH_WIDTH + (GLYPH_WIDTH - 1 - column);
+                if glyph & (1 << bit) == 0 {
+                    continue;
+                }
+                for y in origin.1 + row * scale..origin.1 + (row + 1) * scale {
+                    for x in left + column * scale..left + (column + 1) * scale {
+                        if x < width && y < height {
+                            let idx = (y * width + x) * 4;
+                            image[idx..idx + 4].copy_from_slice(&color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_glyphs_side_by_side() {
+        let width = text_width("1:", 2);
+        assert_eq!(width, 14);
+        let mut image = vec![0; width * GLYPH_HEIGHT * 2 * 4];
+        draw_text(&mut image, width, (0, 0), "1:", 2, [255; 4]);
+
+        let rows: Vec<String> = image
+            .chunks(width * 4)
+            .step_by(2)
+            .map(|row| {
+                row.chunks(4)
+                    .step_by(2)
+                    .map(|pixel| if pixel[3] == 255 { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [".#.....", "##...#.", ".#.....", ".#...#.", "###...."]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::error::Error;
+
+/// Size of the ICONDIR and the single ICONDIRENTRY that precede the image
+const HEADER_SIZE: u32 = 6 + 16;
+/// Size of a BITMAPINFOHEADER
+const BITMAP_HEADER_SIZE: u32 = 40;
+/// Most pixels an icon can have on a side
+pub const MAX_SIDE: usize = 256;
+
+/// Encodes an RGBA image as an ICO file with a single 32-bit BMP image.
+///
+/// Besides the alpha channel, transparent pixels are also set in the AND mask, so the
+/// transparency is kept by readers that ignore the alpha channel.
+pub fn encode(width: usize, height: usize, rgba: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if width == 0 || height == 0 || width > MAX_SIDE || height > MAX_SIDE {
+        return Err(format!("{}x{} is not a valid icon size", width, height).into());
+    }
+
+    // Rows of the AND mask are padded to 32 bits
+    let mask_stride = width.div_ceil(32) * 4;
+    let image_size = (width * height * 4 + mask_stride * height) as u32;
+
+    let mut ico = Vec::with_capacity((HEADER_SIZE + BITMAP_HEADER_SIZE + image_size) as usize);
+
+    // ICONDIR
+    ico.extend_from_slice(&0u16.to_le_bytes());
+    ico.extend_from_slice(&1u16.to_le_bytes()); // Type: icon
+    ico.extend_from_slice(&1u16.to_le_bytes()); // Number of images
+
+    // ICONDIRENTRY, where a size of 0 means 256
+    ico.push(width as u8);
+    ico.push(height as u8);
+    ico.push(0); // No palette
+    ico.push(0);
+    ico.extend_from_slice(&1u16.to_le_bytes()); // Color planes
+    ico.extend_from_slice(&32u16.to_le_bytes()); // Bits per pixel
+    ico.extend_from_slice(&(BITMAP_HEADER_SIZE + image_size).to_le_bytes());
+    ico.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+
+    // BITMAPINFOHEADER, where the height covers both the image and the AND mask
+    ico.extend_from_slice(&BITMAP_HEADER_SIZE.to_le_bytes());
+    ico.extend_from_slice(&(width as i32).to_le_bytes());
+    ico.extend_from_slice(&(height as i32 * 2).to_le_bytes());
+    ico.extend_from_slice(&1u16.to_le_bytes());
+    ico.extend_from_slice(&32u16.to_le_bytes());
+    ico.extend_from_slice(&0u32.to_le_bytes()); // No compression
+    ico.extend_from_slice(&image_size.to_le_bytes());
+    ico.extend_from_slice(&[0; 16]); // Resolution and palette
+
+    // The bitmap is stored bottom-up in BGRA order
+    for row in rgba.chunks(width * 4).rev() {
+        for pixel in row.chunks(4) {
+            ico.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+
+    // AND mask, where a set bit is a transparent pixel
+    for row in rgba.chunks(width * 4).rev() {
+        let mut mask_row = vec![0u8; mask_stride];
+        for (x, pixel) in row.chunks(4).enumerate() {
+            if pixel[3] == 0 {
+                mask_row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        ico.extend_from_slice(&mask_row);
+    }
+
+    Ok(ico)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Decodes the `(rgba, transparent in AND mask)` of a pixel in an icon made by `encode`
+    fn decode_pixel(ico: &[u8], x: usize, y: usize) -> ([u8; 4], bool) {
+        let image = read_u32(ico, 18) as usize;
+        let width = read_u32(ico, image + 4) as usize;
+        let height = read_u32(ico, image + 8) as usize / 2;
+        let bitmap = image + read_u32(ico, image) as usize;
+        let mask = bitmap + width * height * 4;
+        let mask_stride = width.div_ceil(32) * 4;
+
+        // Both the bitmap and the mask are stored bottom-up
+        let row = height - 1 - y;
+        let idx = bitmap + (row * width + x) * 4;
+        let rgba = [ico[idx + 2], ico[idx + 1], ico[idx], ico[idx + 3]];
+        let transparent = ico[mask + row * mask_stride + x / 8] & (0x80 >> (x % 8)) != 0;
+
+        (rgba, transparent)
+    }
+
+    #[test]
+    fn keeps_transparency() {
+        // 3x2 image with one fully transparent pixel at (1, 0)
+        let rgba = vec![
+            255, 0, 0, 255, 0, 0, 0, 0, 0, 255, 0, 255, //
+            0, 0, 255, 255, 10, 20, 30, 255, 255, 255, 255, 128,
+        ];
+
+        let ico = encode(3, 2, &rgba).unwrap();
+
+        assert_eq!(decode_pixel(&ico, 1, 0), ([0, 0, 0, 0], true));
+        assert_eq!(decode_pixel(&ico, 0, 0), ([255, 0, 0, 255], false));
+        assert_eq!(decode_pixel(&ico, 1, 1), ([10, 20, 30, 255], false));
+        // Partially transparent pixels are left to the alpha channel
+        assert_eq!(decode_pixel(&ico, 2, 1), ([255, 255, 255, 128], false));
+    }
+
+    #[test]
+    fn rejects_too_large_images() {
+        assert!(encode(257, 1, &vec![0; 257 * 4]).is_err());
+    }
+}
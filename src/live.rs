@@ -1,37 +1,504 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use futures::sync::mpsc;
+use chrono::{DateTime, TimeZone, Utc};
+
+use futures::sync::{mpsc, oneshot};
 use futures::{Future, Stream};
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use warp::{
     self,
     ws::{Message, WebSocket},
 };
 
-type Listeners = RwLock<HashMap<usize, mpsc::UnboundedSender<Message>>>;
+use crate::config::{Config, Sizes, SlowListenerPolicy};
+use crate::{db, logo};
+
+struct Listener {
+    tx: mpsc::UnboundedSender<Message>,
+    // Number of messages in the channel that are not yet sent on the websocket
+    queued: Arc<AtomicUsize>,
+    // Closes the websocket when sent or dropped
+    disconnect: oneshot::Sender<()>,
+    // Pixels that must have changed since the last logo it was sent, for it to get an update
+    min_changed_pixels: usize,
+    // The last logo it was sent, or had when it connected, when it only wants larger changes
+    last_sent: Mutex<Option<Vec<u8>>>,
+    // Sizes it gets a bundle of renders of on each change, instead of the logo at size 1
+    sizes: Vec<u32>,
+}
+
+impl Listener {
+    /// Whether the websocket is closed or has failed. The websocket task drops the receiver when
+    /// sending fails, and the connection drops the disconnect receiver when it ends.
+    fn is_gone(&self) -> bool {
+        self.tx.is_closed() || self.disconnect.is_canceled()
+    }
+
+    /// Whether the logo has changed enough since the listener last got it
+    fn wants(&self, logo_png: &[u8]) -> bool {
+        if self.min_changed_pixels == 0 {
+            return true;
+        }
+        match *self.last_sent.lock() {
+            Some(ref last_sent) => logo::changed_pixels(last_sent, logo_png)
+                .map(|changed| changed >= self.min_changed_pixels)
+                .unwrap_or(true),
+            None => true,
+        }
+    }
+}
+
+/// Seconds of messages at the full rate that a listener may send in a burst
+const MESSAGE_BURST_SECONDS: f64 = 5.0;
+
+/// Token bucket limiting the messages received from a listener
+struct MessageLimit {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl MessageLimit {
+    fn new(rate: u32) -> Self {
+        let rate = f64::from(rate);
+        MessageLimit {
+            rate,
+            tokens: rate * MESSAGE_BURST_SECONDS,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token for a message, returning false if the listener is flooding
+    fn allow(&mut self) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate * MESSAGE_BURST_SECONDS);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+type Listeners = RwLock<HashMap<usize, Listener>>;
 
 // Next id for use by a websocket listener
 static NEXT_LISTENER_ID: AtomicUsize = AtomicUsize::new(1);
 lazy_static! {
     // Channels for each of the websocket listeners
     static ref LISTENERS: Listeners = RwLock::new(HashMap::new());
+    // The latest change waiting for the broadcast window to end, if one has started
+    static ref PENDING_UPDATE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
 }
 
-pub fn send_update(logo_png: &Vec<u8>) {
-    for tx in LISTENERS.read().values() {
-        if let Err(err) = tx.unbounded_send(Message::binary(logo_png.clone())) {
+/// How busy the websocket is, for the health check
+#[derive(Serialize)]
+pub struct Load {
+    listeners: usize,
+    /// Updates waiting to be sent to the listeners
+    queued_updates: usize,
+}
+
+pub fn load() -> Load {
+    let listeners = LISTENERS.read();
+    Load {
+        listeners: listeners.len(),
+        queued_updates: listeners
+            .values()
+            .map(|listener| listener.queued.load(Ordering::Relaxed))
+            .sum(),
+    }
+}
+
+/// Sends a change of the logo to the listeners. With a `broadcast_window`, the first change starts
+/// the window and only the latest change when it ends is sent.
+pub fn send_update(config: &Config, logo_png: &[u8]) {
+    if config.broadcast_window == 0 {
+        return broadcast(config, logo_png);
+    }
+
+    let mut pending = PENDING_UPDATE.lock();
+    let window_open = pending.is_some();
+    *pending = Some(logo_png.to_vec());
+    if !window_open {
+        let config = config.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(config.broadcast_window));
+            let latest = PENDING_UPDATE.lock().take();
+            if let Some(logo_png) = latest {
+                broadcast(&config, &logo_png);
+            }
+        });
+    }
+}
+
+fn broadcast(config: &Config, logo_png: &[u8]) {
+    // The bundles are rendered before the listeners are locked, as encoding them takes a while and
+    // new listeners can't be added in the meantime
+    let size_sets: HashSet<Vec<u32>> = LISTENERS
+        .read()
+        .values()
+        .filter(|listener| !listener.sizes.is_empty())
+        .map(|listener| listener.sizes.clone())
+        .collect();
+    let bundles = renders_messages(config, size_sets);
+
+    let mut slow_listeners = vec![];
+
+    for (id, listener) in LISTENERS.read().iter() {
+        if !listener.wants(logo_png) {
+            continue;
+        }
+        if listener.queued.load(Ordering::Relaxed) >= config.listener_buffer {
+            match config.slow_listener_policy {
+                // The oldest message is skipped when the listener gets to it
+                SlowListenerPolicy::DropOldest => {}
+                SlowListenerPolicy::DropUpdate => continue,
+                SlowListenerPolicy::Disconnect => {
+                    slow_listeners.push(*id);
+                    continue;
+                }
+            }
+        }
+
+        let message = if listener.sizes.is_empty() {
+            Message::binary(logo_png.to_vec())
+        } else {
+            // A listener that connected after the bundles were rendered gets the next update
+            match bundles.get(&listener.sizes) {
+                Some(bundle) => Message::text(bundle.clone()),
+                None => continue,
+            }
+        };
+        listener.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = listener.tx.unbounded_send(message) {
             eprintln!("Error sending: {:?}", err);
         }
+        if listener.min_changed_pixels > 0 {
+            *listener.last_sent.lock() = Some(logo_png.to_vec());
+        }
     }
+
+    for id in slow_listeners {
+        if let Some(listener) = LISTENERS.write().remove(&id) {
+            eprintln!("disconnecting slow listener: {}", id);
+            // The listener might have disconnected by itself in the meantime
+            let _ = listener.disconnect.send(());
+        }
+    }
+}
+
+/// The `Renders` message for each of the sets of sizes, as json, rendering every size once. The
+/// sets with a size that can't be rendered are left out.
+fn renders_messages(config: &Config, size_sets: HashSet<Vec<u32>>) -> HashMap<Vec<u32>, String> {
+    let mut renders = HashMap::new();
+    for &size in size_sets.iter().flatten() {
+        if renders.contains_key(&size) {
+            continue;
+        }
+        let options = logo::LogoOptions::default()
+            .with_size(size)
+            .with_format(logo::Format::Png);
+        let render = match logo::get_logo_image(config, &options) {
+            Ok(png) => Some(base64::encode(&png)),
+            Err(err) => {
+                eprintln!("Error rendering the logo at size {}: {}", size, err);
+                None
+            }
+        };
+        renders.insert(size, render);
+    }
+
+    size_sets
+        .into_iter()
+        .filter_map(|sizes| {
+            let message = Renders {
+                kind: "renders",
+                renders: sizes
+                    .iter()
+                    .map(|size| renders[size].as_deref().map(|png| (*size, png)))
+                    .collect::<Option<_>>()?,
+            };
+            let message = serde_json::to_string(&message).ok()?;
+            Some((sizes, message))
+        })
+        .collect()
 }
 
-pub fn listener_connected(ws: WebSocket) -> impl Future<Item = (), Error = ()> {
+/// How often listeners that are gone are looked for between updates
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Removes the listeners whose websocket is closed or has failed. They are otherwise only found
+/// when an update is sent, which can take long when the logo doesn't change.
+pub fn sweep_listeners() {
+    let mut listeners = LISTENERS.write();
+    let before = listeners.len();
+    listeners.retain(|_, listener| !listener.is_gone());
+
+    let removed = before - listeners.len();
+    if removed > 0 {
+        eprintln!(
+            "removed {} dead listeners, {} left",
+            removed,
+            listeners.len()
+        );
+    }
+}
+
+/// Most sizes a listener can ask for, as every change is rendered at each of them
+const MAX_LIVE_SIZES: usize = 8;
+
+/// Options of the `/live` websocket
+#[derive(Debug, Deserialize, Clone)]
+pub struct LiveOptions {
+    /// Send a `Hello` text message before the logo updates
+    #[serde(default)]
+    hello: bool,
+    /// Only send updates where at least this many pixels of the full logo at size 1 changed
+    /// since the last update that was sent
+    #[serde(default)]
+    min_changed_pixels: usize,
+    /// Comma separated sizes to send a bundle of renders at on each change, instead of the logo
+    /// at size 1
+    sizes: Option<String>,
+}
+
+impl LiveOptions {
+    /// The sizes asked for in order, without repeats, or none for the plain updates
+    fn sizes(&self) -> Result<Vec<u32>, String> {
+        let mut sizes = match self.sizes {
+            Some(ref sizes) => sizes.parse::<Sizes>()?.0,
+            None => vec![],
+        };
+        sizes.sort_unstable();
+        sizes.dedup();
+        Ok(sizes)
+    }
+
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        let sizes = self.sizes()?;
+        if sizes.len() > MAX_LIVE_SIZES {
+            return Err(format!("at most {} sizes can be asked for", MAX_LIVE_SIZES));
+        }
+        for size in sizes {
+            if size == 0 {
+                return Err("sizes must be at least 1".to_owned());
+            }
+            logo::LogoOptions::default()
+                .with_size(size)
+                .validate(config)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first message to listeners that ask for it, telling them what the server sends. It is
+/// text, while the logo updates are binary, so clients can tell it apart.
+#[derive(Serialize)]
+struct Hello {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    server: &'static str,
+    version: &'static str,
+    /// When the live logo last changed, which tells its versions apart
+    logo_changed_at: Option<DateTime<Utc>>,
+    /// Content types of the binary messages
+    formats: &'static [&'static str],
+}
+
+/// The update to listeners that ask for `sizes`, as a text message
+#[derive(Serialize)]
+struct Renders<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Base64 encoded PNGs of the logo, keyed by size
+    renders: BTreeMap<u32, &'a str>,
+}
+
+pub fn listener_connected(
+    config: Arc<Config>,
+    ip: Option<IpAddr>,
+    options: LiveOptions,
+    ws: WebSocket,
+) -> impl Future<Item = (), Error = ()> {
+    // Checked by the route already
+    let sizes = options.sizes().unwrap_or_default();
+    let hello = if options.hello {
+        let hello = Hello {
+            kind: "hello",
+            server: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            logo_changed_at: logo::changed_at(),
+            formats: if sizes.is_empty() {
+                &["image/png"]
+            } else {
+                &[]
+            },
+        };
+        serde_json::to_string(&hello).ok().map(Message::text)
+    } else {
+        None
+    };
+    serve_listener(
+        config,
+        ip,
+        ws,
+        hello,
+        options.min_changed_pixels,
+        sizes,
+        None,
+    )
+}
+
+/// Where and how fast to play back the history
+#[derive(Debug, Deserialize, Copy, Clone)]
+pub struct PlaybackOptions {
+    /// Time to start from, or the start of the history
+    from: Option<DateTime<Utc>>,
+    /// How many times faster than it happened to play the history back
+    #[serde(default = "default_speed")]
+    speed: f64,
+    /// Keep sending live updates once the history has been played back, instead of closing
+    #[serde(default)]
+    live: bool,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+impl PlaybackOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.speed.is_finite() && self.speed > 0.0) {
+            return Err("speed must be a positive number".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Entries fetched from the database at a time during playback
+const PLAYBACK_BATCH_SIZE: i64 = 100;
+
+/// Longest a playback sleeps before checking that the listener is still there
+const PLAYBACK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Playbacks that are running, bounded by `max_playbacks`
+static PLAYBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the `max_playbacks` playbacks that can run at once, given back when it is dropped
+pub struct PlaybackSlot;
+
+impl PlaybackSlot {
+    pub fn take(max_playbacks: usize) -> Option<Self> {
+        PLAYBACKS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |playbacks| {
+                Some(playbacks + 1).filter(|&playbacks| playbacks <= max_playbacks)
+            })
+            .ok()
+            .map(|_| PlaybackSlot)
+    }
+}
+
+impl Drop for PlaybackSlot {
+    fn drop(&mut self) {
+        PLAYBACKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A listener that gets the stored history sent at the pace it happened, sped up by
+/// `options.speed`, before it (optionally) gets the live updates. The slot is held until the
+/// history has been played back.
+pub fn playback_connected(
+    config: Arc<Config>,
+    ip: Option<IpAddr>,
+    options: PlaybackOptions,
+    slot: PlaybackSlot,
+    ws: WebSocket,
+) -> impl Future<Item = (), Error = ()> {
+    serve_listener(config, ip, ws, None, 0, vec![], Some((options, slot)))
+}
+
+/// Sends the history to a listener on its own thread, as the database is blocking, and then
+/// hands it over to the live updates. Dropping the listener at the end closes the websocket.
+fn play_back(config: Arc<Config>, id: usize, listener: Listener, options: PlaybackOptions) {
+    let start = options.from.unwrap_or_else(|| Utc.timestamp(0, 0));
+    // Ids start at 1, so this includes everything at the start time
+    let mut cursor = (start, 0);
+    let mut last_time: Option<DateTime<Utc>> = None;
+
+    loop {
+        let frames = match db::get_frames_after(&config, cursor, PLAYBACK_BATCH_SIZE) {
+            Ok(frames) => frames,
+            Err(err) => {
+                eprintln!("Error playing back history to listener {}: {}", id, err);
+                return;
+            }
+        };
+        if frames.is_empty() {
+            break;
+        }
+
+        for frame in frames {
+            if let Some(last_time) = last_time {
+                let gap = (frame.time - last_time).to_std().unwrap_or_default();
+                let wait_until = Instant::now() + gap.div_f64(options.speed);
+                while let Some(wait) = wait_until.checked_duration_since(Instant::now()) {
+                    if listener.is_gone() {
+                        return;
+                    }
+                    thread::sleep(wait.min(PLAYBACK_CHECK_INTERVAL));
+                }
+            }
+            last_time = Some(frame.time);
+            cursor = (frame.time, frame.id);
+
+            listener.queued.fetch_add(1, Ordering::Relaxed);
+            if listener
+                .tx
+                .unbounded_send(Message::binary(frame.png))
+                .is_err()
+            {
+                // The listener is gone
+                return;
+            }
+        }
+    }
+
+    if options.live && !listener.is_gone() {
+        LISTENERS.write().insert(id, listener);
+    }
+}
+
+fn serve_listener(
+    config: Arc<Config>,
+    ip: Option<IpAddr>,
+    ws: WebSocket,
+    hello: Option<Message>,
+    min_changed_pixels: usize,
+    sizes: Vec<u32>,
+    playback: Option<(PlaybackOptions, PlaybackSlot)>,
+) -> impl Future<Item = (), Error = ()> {
     // Use a counter to assign a new unique ID for this user.
     let my_id = NEXT_LISTENER_ID.fetch_add(1, Ordering::Relaxed);
 
-    eprintln!("new listener: {}", my_id);
+    let ip = ip.map_or_else(|| "unknown address".to_owned(), |ip| ip.to_string());
+    eprintln!("new listener: {} ({})", my_id, ip);
+
+    let mut message_limit = MessageLimit::new(config.listener_message_rate);
 
     // Split the socket into a sender and receive of messages.
     let (listener_ws_tx, listener_ws_rx) = ws.split();
@@ -39,30 +506,81 @@ pub fn listener_connected(ws: WebSocket) -> impl Future<Item = (), Error = ()> {
     // Use an unbounded channel to handle buffering and flushing of messages
     // to the websocket...
     let (tx, rx) = mpsc::unbounded();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let rx_queued = queued.clone();
+    let listener_buffer = config.listener_buffer;
     warp::spawn(
-        rx.map_err(|()| -> warp::Error { unreachable!("unbounded rx never errors") })
-            .forward(listener_ws_tx)
-            .map(|_tx_rx| ())
-            .map_err(|ws_err| eprintln!("websocket send error: {}", ws_err)),
+        rx.filter(move |_| {
+            // Skip the oldest messages while the listener is behind by more than the buffer
+            rx_queued.fetch_sub(1, Ordering::Relaxed) <= listener_buffer
+        })
+        .map_err(|()| -> warp::Error { unreachable!("unbounded rx never errors") })
+        .forward(listener_ws_tx)
+        .map(|_tx_rx| ())
+        .map_err(|ws_err| eprintln!("websocket send error: {}", ws_err)),
     );
 
-    // Save the sender in our list of connected users.
-    LISTENERS.write().insert(my_id, tx);
+    if let Some(hello) = hello {
+        queued.fetch_add(1, Ordering::Relaxed);
+        // The receiver is still there, so this can't fail
+        let _ = tx.unbounded_send(hello);
+    }
+
+    let (disconnect, disconnected) = oneshot::channel();
+
+    // Changes are counted from the logo the listener already has
+    let last_sent = if min_changed_pixels > 0 && logo::is_ready() {
+        logo::get_logo_image(&config, &logo::LogoOptions::default()).ok()
+    } else {
+        None
+    };
+    let listener = Listener {
+        tx,
+        queued,
+        disconnect,
+        min_changed_pixels,
+        last_sent: Mutex::new(last_sent),
+        sizes,
+    };
+    match playback {
+        Some((options, slot)) => {
+            thread::spawn(move || {
+                play_back(config, my_id, listener, options);
+                drop(slot);
+            });
+        }
+        // Save the sender in our list of connected users.
+        None => {
+            LISTENERS.write().insert(my_id, listener);
+        }
+    }
 
     // Return a `Future` that is basically a state machine managing
     // this specific user's connection.
 
     listener_ws_rx
+        // Stop listening to a listener that floods us with messages
+        .take_while(move |_| {
+            let allowed = message_limit.allow();
+            if !allowed {
+                eprintln!("disconnecting flooding listener: {}", my_id);
+            }
+            Ok(allowed)
+        })
         // Every time the user sends a message, broadcast it to
         // all other users...
         .for_each(move |msg| {
             println!("Got message from listener: {:?}", msg);
             Ok(())
         })
+        // ...or until they are disconnected for being too slow
+        .select2(disconnected.then(|_| Ok(())))
+        .map(|_| ())
+        .map_err(|err| err.split().0)
         // for_each will keep processing as long as the user stays
         // connected. Once they disconnect, then...
         .then(move |result| {
-            eprintln!("good bye listener: {}", my_id);
+            eprintln!("good bye listener: {} ({})", my_id, ip);
 
             // Stream closed up, so remove from the user list
             LISTENERS.write().remove(&my_id);
@@ -1,79 +1,2144 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::mem;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
-use serde::Deserialize;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 
-use crate::{db, live};
+use crate::budget::Budget;
+use crate::coalesce::Coalescer;
+use crate::color::{Color, Palette};
+use crate::config::{Config, OversizedLogo, Preset, Sizes};
+use crate::upstream::Fetcher;
+use crate::{db, font, ico, live, lottie, metrics, mjpeg, zip};
+
+/// How much larger than the output a smooth logo is rendered before downsampling
+const SMOOTH_FACTOR: u32 = 4;
+
+/// Quality of JPEG images, where 90 and above keeps the full color resolution
+const JPEG_QUALITY: u8 = 90;
+
+/// How long after the logo changed `animate` shows the change
+const ANIMATION_WINDOW: Duration = Duration::from_secs(10);
+
+/// Frames of the fade from the previous logo to the live one
+const ANIMATION_FRAMES: usize = 8;
+
+/// Hundredths of a second each frame of the fade is shown
+const ANIMATION_FRAME_DELAY: u16 = 6;
+
+/// How much longer than three poll intervals the poller may go without finishing a poll before
+/// it counts as stalled, which leaves time for a slow upstream
+const POLL_STALL_GRACE: Duration = Duration::from_secs(30);
+
+/// Largest blur radius of a drop shadow, in pixels
+pub const MAX_SHADOW_BLUR: u32 = 32;
+
+/// Number of 8x8 panels of each character that fit in the image
+const MAX_PANELS: [usize; 7] = [3, 7, 8, 5, 7, 7, 7];
+
+/// Number of characters in the logo
+pub const CHARACTERS: i16 = MAX_PANELS.len() as i16;
+
+/// Bitmask with a bit for every character
+pub const ALL_CHARACTERS: i16 = (1 << CHARACTERS) - 1;
+
+/// Sizes of the PNG with the default options that are rendered when the logo changes, as most
+/// requests are for them
+const PRERENDERED_SIZES: [u32; 3] = [1, 2, 4];
+
+/// The live logo rendered at `PRERENDERED_SIZES`
+struct Prerendered {
+    /// The generation of the config they were rendered with, as a reload can change how the logo
+    /// is drawn
+    generation: u64,
+    images: Vec<(u32, Vec<u8>)>,
+}
+
+/// The icons of `/api/v1/icons.zip`, by file name, with their side and the margin around the logo
+/// in percent of the side. Icons that are shown as tiles and app icons get a margin, so the logo
+/// doesn't touch the edges once they are rounded.
+const ICONS: [(&str, usize, usize); 6] = [
+    ("favicon-16x16.png", 16, 0),
+    ("favicon-32x32.png", 32, 0),
+    ("favicon-48x48.png", 48, 0),
+    ("apple-touch-icon.png", 180, 10),
+    ("android-chrome-192x192.png", 192, 10),
+    ("android-chrome-512x512.png", 512, 10),
+];
+
+/// Largest size picked from a device pixel ratio, which also covers the screens with a higher one
+const MAX_DPR_SIZE: u32 = 4;
+
+/// Size the logo is rendered at for the icons, which is large enough to only ever scale it down
+const ICON_LOGO_SIZE: u32 = 16;
+
+/// Options of the icon set
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct IconOptions {
+    /// Color behind the logo, as `rrggbb` or `rrggbbaa`. The apple touch icon is always made
+    /// opaque, as iOS fills transparent pixels with black.
+    background: Option<String>,
+    /// Name of the app in the web manifest
+    name: Option<String>,
+}
+
+impl IconOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ref background) = self.background {
+            background.parse::<Color>()?;
+        }
+        Ok(())
+    }
+}
+
+/// The icon set of the live logo, which is made again once the logo changes
+struct IconSet {
+    logo: LogoResponse,
+    srgb: bool,
+    options: IconOptions,
+    zip: Vec<u8>,
+}
+
+/// Holds back the changes to store in the history, so at most one is stored per
+/// `history_interval`
+#[derive(Default)]
+struct HistoryWrites {
+    last_saved: Option<Instant>,
+    /// The latest change that hasn't been stored, which replaces the ones before it
+    pending: Option<Vec<u8>>,
+}
+
+impl HistoryWrites {
+    /// Takes the change to store now, if there is one and the interval has passed
+    fn take_due(&mut self, changed: Option<Vec<u8>>, interval: Duration) -> Option<Vec<u8>> {
+        if let Some(changed) = changed {
+            self.pending = Some(changed);
+        }
+        if self
+            .last_saved
+            .is_some_and(|last_saved| last_saved.elapsed() < interval)
+        {
+            return None;
+        }
+        let pending = self.pending.take()?;
+        self.last_saved = Some(Instant::now());
+        Some(pending)
+    }
+}
 
 lazy_static! {
     // Last logo fetched from the api
     static ref LOGO_CACHE: RwLock<LogoResponse> = RwLock::new(LogoResponse { logo: vec![] });
+    // The logo before the last change, and when it changed
+    static ref PREVIOUS_LOGO: RwLock<Option<(LogoResponse, Instant)>> = RwLock::new(None);
+    // When the poller last finished a poll, or was started
+    static ref LAST_POLL: RwLock<Instant> = RwLock::new(Instant::now());
+    // When the live logo last changed, or was first fetched
+    static ref LOGO_CHANGED_AT: RwLock<Option<DateTime<Utc>>> = RwLock::new(None);
+    // The live logo at the common sizes, or `None` until they are rendered after a change
+    static ref PRERENDERED: RwLock<Option<Prerendered>> = RwLock::new(None);
+    // Changes waiting to be stored in the history
+    static ref HISTORY_WRITES: Mutex<HistoryWrites> = Mutex::new(HistoryWrites::default());
+    // The icon set last asked for, if the logo hasn't changed since
+    static ref ICON_SET: RwLock<Option<IconSet>> = RwLock::new(None);
+    // Renders of the live logo that are running, so identical requests share them
+    static ref RENDERS: Coalescer = Coalescer::new();
+    // The light of each sRGB channel value, between 0 and 1
+    static ref SRGB_TO_LINEAR: [f64; 256] = {
+        let mut table = [0.0; 256];
+        for (channel, value) in table.iter_mut().enumerate() {
+            let srgb = channel as f64 / 255.0;
+            *value = if srgb <= 0.040_45 {
+                srgb / 12.92
+            } else {
+                ((srgb + 0.055) / 1.055).powf(2.4)
+            };
+        }
+        table
+    };
+}
+
+#[derive(Debug, Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Png,
+    Ico,
+    Jpeg,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "png" => Ok(Format::Png),
+            "ico" => Ok(Format::Ico),
+            "jpeg" => Ok(Format::Jpeg),
+            _ => Err("expected png, ico or jpeg".to_owned()),
+        }
+    }
+}
+
+impl Format {
+    /// How the format is named in the `format` option
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Ico => "ico",
+            Format::Jpeg => "jpeg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Ico => "image/x-icon",
+            Format::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Which ways to mirror the logo
+#[derive(Debug, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Flip {
+    /// Left to right
+    H,
+    /// Top to bottom
+    V,
+    /// Both, which turns it upside down
+    Hv,
+}
+
+impl FromStr for Flip {
+    type Err = String;
+
+    fn from_str(flip: &str) -> Result<Self, Self::Err> {
+        match flip {
+            "h" => Ok(Flip::H),
+            "v" => Ok(Flip::V),
+            "hv" => Ok(Flip::Hv),
+            _ => Err("expected h, v or hv".to_owned()),
+        }
+    }
+}
+
+/// A drop shadow behind the logo, written as `blur:rrggbbaa`. The blur radius is also how far the
+/// shadow falls down and to the right.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Shadow {
+    blur: u32,
+    color: Color,
+}
+
+impl FromStr for Shadow {
+    type Err = String;
+
+    fn from_str(shadow: &str) -> Result<Self, Self::Err> {
+        let mut parts = shadow.splitn(2, ':');
+        let blur = parts.next().unwrap_or_default();
+        let color = parts
+            .next()
+            .ok_or_else(|| format!("{} is not a valid shadow, expected blur:color", shadow))?;
+
+        let blur = blur
+            .parse()
+            .ok()
+            .filter(|blur| (1..=MAX_SHADOW_BLUR).contains(blur))
+            .ok_or_else(|| format!("shadow blur must be between 1 and {}", MAX_SHADOW_BLUR))?;
+
+        Ok(Shadow {
+            blur,
+            color: color.parse()?,
+        })
+    }
+}
+
+/// The width and height of an image in pixels
+type Dimensions = (u64, u64);
+
+/// The size of the canvas that `tile` fills with copies of the logo
+#[derive(Debug, Copy, Clone)]
+struct Tile {
+    width: u32,
+    height: u32,
+}
+
+impl FromStr for Tile {
+    type Err = String;
+
+    fn from_str(tile: &str) -> Result<Self, Self::Err> {
+        let mut parts = tile.splitn(2, 'x');
+        let size = |part: Option<&str>| part.and_then(|part| part.parse().ok());
+        match (size(parts.next()), size(parts.next())) {
+            (Some(width), Some(height)) => Ok(Tile { width, height }),
+            _ => Err(format!("{} is not a valid tile, expected WxH", tile)),
+        }
+    }
+}
+
+// Options for rendering the logo, from the query string or the arguments of `render`
+#[derive(Debug, Deserialize, StructOpt, Clone, Default, PartialEq)]
+pub struct LogoOptions {
+    /// Pixels per logical pixel of the logo
+    #[structopt(long)]
+    size: Option<u32>,
+    /// Render only this character, counting from 0
+    #[structopt(long)]
+    character: Option<usize>,
+    /// Leave out the empty rows above a single character
+    #[serde(default)]
+    #[structopt(long)]
+    crop: bool,
+    /// Logical pixels of empty space to leave on every side of a single character
+    #[structopt(long)]
+    pad: Option<u32>,
+    /// Crop away the transparent margins around the logo
+    #[serde(default)]
+    #[structopt(long)]
+    trim: bool,
+    /// Transparent pixels to leave around the logo when trimming
+    #[structopt(long)]
+    padding: Option<u32>,
+    /// Soften the edges between the logo pixels, instead of keeping them crisp
+    #[serde(default)]
+    #[structopt(long)]
+    smooth: bool,
+    /// Image format: png, ico or jpeg, or else the configured `default_format`
+    #[structopt(long)]
+    format: Option<Format>,
+    /// Name of a color transform preset to apply
+    #[structopt(long)]
+    preset: Option<String>,
+    /// Scale the logo to fit, centered, in a square of this many pixels
+    #[structopt(long)]
+    avatar: Option<u32>,
+    /// Color behind the logo in the avatar square, as `rrggbb` or `rrggbbaa`
+    #[structopt(long)]
+    background: Option<String>,
+    /// Color that transparent pixels become in formats without transparency, as `rrggbb`
+    #[structopt(long)]
+    jpeg_bg: Option<String>,
+    /// Drop shadow behind the logo, as `blur:rrggbbaa`
+    #[structopt(long)]
+    shadow: Option<String>,
+    /// Color to draw the whole logo in, as `rrggbb`
+    #[structopt(long)]
+    tint: Option<String>,
+    /// Mirror the logo: h, v or hv
+    #[structopt(long)]
+    flip: Option<Flip>,
+    /// Colors to draw single characters in, as `character:rrggbb` separated by commas
+    #[structopt(long)]
+    char_color: Option<String>,
+    /// Repeat the logo to fill a canvas of this size, as `WxH`
+    #[structopt(long)]
+    tile: Option<String>,
+    /// Transparent pixels between the copies of the logo when tiling
+    #[structopt(long)]
+    gap: Option<u32>,
+    /// Largest image to send in bytes, trying smaller PNG encodings when the usual one is larger
+    #[structopt(long)]
+    max_bytes: Option<usize>,
+    /// Draw each character in the next color of the configured rainbow palette
+    #[serde(default)]
+    #[structopt(long)]
+    rainbow: bool,
+    /// Bits per channel of PNGs: 8 or 16
+    #[structopt(long)]
+    depth: Option<u8>,
+    /// Fade from the previous logo when it just changed, for clients that accept APNG
+    #[serde(default)]
+    #[structopt(skip)]
+    animate: bool,
+}
+
+impl LogoOptions {
+    pub fn size(&self) -> Option<u32> {
+        self.size
+    }
+
+    pub fn format(&self) -> Format {
+        self.format.unwrap_or_default()
+    }
+
+    pub fn animate(&self) -> bool {
+        self.animate
+    }
+
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    fn bit_depth(&self) -> png::BitDepth {
+        match self.depth {
+            Some(16) => png::BitDepth::Sixteen,
+            _ => png::BitDepth::Eight,
+        }
+    }
+
+    /// The color of each character in `char_color`, or `None` for the ones that keep their own
+    fn char_colors(&self) -> Result<[Option<Color>; MAX_PANELS.len()], String> {
+        let mut colors = [None; MAX_PANELS.len()];
+        let char_color = match self.char_color {
+            Some(ref char_color) => char_color,
+            None => return Ok(colors),
+        };
+        for char_color in char_color.split(',') {
+            let mut parts = char_color.splitn(2, ':');
+            let character = parts.next().unwrap_or_default();
+            let color = parts.next().ok_or_else(|| {
+                format!(
+                    "{} is not a valid character color, expected character:color",
+                    char_color
+                )
+            })?;
+            let character = character
+                .parse::<usize>()
+                .ok()
+                .filter(|&character| character < MAX_PANELS.len())
+                .ok_or_else(|| format!("{} is not a valid character", character))?;
+            colors[character] = Some(color.parse()?);
+        }
+        Ok(colors)
+    }
+
+    /// The options with `char_color` giving each character the next color of the palette, except
+    /// the characters that `char_color` already colors
+    fn with_rainbow(&self, palette: &Palette) -> LogoOptions {
+        let mut char_color: Vec<String> = palette
+            .0
+            .iter()
+            .cycle()
+            .take(if palette.0.is_empty() {
+                0
+            } else {
+                CHARACTERS as usize
+            })
+            .enumerate()
+            .map(|(character, color)| format!("{}:{}", character, color))
+            .collect();
+        // The colors that come later win
+        char_color.extend(self.char_color.clone());
+        LogoOptions {
+            char_color: Some(char_color.join(",")).filter(|char_color| !char_color.is_empty()),
+            rainbow: false,
+            ..self.clone()
+        }
+    }
+
+    pub fn with_format(self, format: Format) -> Self {
+        LogoOptions {
+            format: Some(format),
+            ..self
+        }
+    }
+
+    pub fn with_size(self, size: u32) -> Self {
+        LogoOptions {
+            size: Some(size),
+            ..self
+        }
+    }
+
+    /// The options in `format`, unless they ask for a format
+    pub fn with_default_format(self, format: Format) -> Self {
+        LogoOptions {
+            format: Some(self.format.unwrap_or(format)),
+            ..self
+        }
+    }
+
+    /// Picks the size matching the device pixel ratio of a client hint, unless a size was asked
+    /// for. It is rounded to whole pixels, and is the nearest of the `allowed_sizes`.
+    pub fn with_device_pixel_ratio(self, dpr: f64, allowed_sizes: &Sizes) -> Self {
+        if self.size.is_some() {
+            return self;
+        }
+        let size = (dpr.round() as u32).clamp(1, MAX_DPR_SIZE);
+        LogoOptions {
+            size: Some(allowed_sizes.nearest(size)),
+            ..self
+        }
+    }
+
+    /// Checks the options that can't be checked when parsing them
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        if let Some(ref preset) = self.preset {
+            if config.preset(preset).is_none() {
+                return Err(format!("{} is not a known preset", preset));
+            }
+        }
+        if let Some(size) = self.size {
+            if size == 0 {
+                return Err("size must be at least 1".to_owned());
+            }
+            if !config.allowed_sizes.allows(size) {
+                return Err(format!("size must be one of {}", config.allowed_sizes));
+            }
+        }
+        if self.pad.is_some() && self.character.is_none() {
+            return Err("pad only applies to a single character".to_owned());
+        }
+        if let Some(avatar) = self.avatar {
+            if avatar == 0 || avatar > config.max_image_size {
+                return Err(format!(
+                    "avatar must be between 1 and {}",
+                    config.max_image_size
+                ));
+            }
+        }
+        if let Some(ref background) = self.background {
+            background.parse::<Color>()?;
+        }
+        if let Some(ref jpeg_bg) = self.jpeg_bg {
+            jpeg_bg.parse::<Color>()?;
+        }
+        if let Some(ref shadow) = self.shadow {
+            shadow.parse::<Shadow>()?;
+        }
+        if let Some(ref tint) = self.tint {
+            tint.parse::<Color>()?;
+        }
+        if let Some(ref tile) = self.tile {
+            let tile = tile.parse::<Tile>()?;
+            let sizes = 1..=config.max_image_size;
+            if !sizes.contains(&tile.width) || !sizes.contains(&tile.height) {
+                return Err(format!(
+                    "tile sides must be between 1 and {}",
+                    config.max_image_size
+                ));
+            }
+        }
+        if let Some(depth) = self.depth {
+            if depth != 8 && depth != 16 {
+                return Err(format!("depth must be 8 or 16, not {}", depth));
+            }
+        }
+        self.char_colors()?;
+        let (width, height) = self.dimensions()?;
+        let max_image_size = u64::from(config.max_image_size);
+        if width > max_image_size || height > max_image_size {
+            return Err(format!(
+                "The image would be {}x{} pixels, but at most {} pixels on a side are supported",
+                width, height, config.max_image_size
+            ));
+        }
+        if self.format() == Format::Ico {
+            let (width, height) = self.output_dimensions()?;
+            let max_side = ico::MAX_SIDE as u64;
+            if width > max_side || height > max_side {
+                return Err(format!(
+                    "The icon would be {}x{} pixels, but icons are at most {} pixels on a side",
+                    width,
+                    height,
+                    ico::MAX_SIDE
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The width and height of the largest image rendering the logo makes, before an avatar or
+    /// tile puts it on a canvas of its own size
+    fn dimensions(&self) -> Result<Dimensions, String> {
+        let (rendered, logo) = self.logo_dimensions()?;
+        Ok((rendered.0.max(logo.0), rendered.1.max(logo.1)))
+    }
+
+    /// The most pixels the encoded image can have on each side
+    fn output_dimensions(&self) -> Result<Dimensions, String> {
+        if let Some(ref tile) = self.tile {
+            let tile = tile.parse::<Tile>()?;
+            return Ok((u64::from(tile.width), u64::from(tile.height)));
+        }
+        if let Some(avatar) = self.avatar {
+            return Ok((u64::from(avatar), u64::from(avatar)));
+        }
+        Ok(self.logo_dimensions()?.1)
+    }
+
+    /// The size the logo is rendered at, and the size of the logo once it is drawn. A smooth logo
+    /// is rendered at `SMOOTH_FACTOR` times its size, and trimming with padding and shadows make
+    /// it larger.
+    fn logo_dimensions(&self) -> Result<(Dimensions, Dimensions), String> {
+        let (width, height) = match self.character {
+            None => (152, 32),
+            Some(character) => {
+                // Cropping only makes it smaller
+                let pad = u64::from(self.pad.unwrap_or(0)) * 2;
+                let width = if character == 0 { 8 } else { 24 };
+                (width + pad, 32 + pad)
+            }
+        };
+        let size = u64::from(self.size.unwrap_or(1));
+        let (mut width, mut height) = (width * size, height * size);
+        let scale = if self.smooth {
+            u64::from(SMOOTH_FACTOR)
+        } else {
+            1
+        };
+        let rendered = (width * scale, height * scale);
+
+        if self.trim {
+            let padding = u64::from(self.padding.unwrap_or(0)) * 2;
+            width += padding;
+            height += padding;
+        }
+        if let Some(ref shadow) = self.shadow {
+            let blur = u64::from(shadow.parse::<Shadow>()?.blur) * 3;
+            width += blur;
+            height += blur;
+        }
+        Ok((rendered, (width, height)))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Eq, PartialEq)]
+pub struct LogoResponse {
+    logo: Vec<Vec<Vec<String>>>,
+}
+
+impl LogoResponse {
+    /// Checks that every panel fits in the image, for logos that don't come from the api
+    pub fn validate(&self) -> Result<(), String> {
+        if self.logo.len() > MAX_PANELS.len() {
+            return Err(format!(
+                "The logo has {} characters, but at most {} are supported",
+                self.logo.len(),
+                MAX_PANELS.len()
+            ));
+        }
+        for (char_index, (chr, &max_panels)) in self.logo.iter().zip(&MAX_PANELS).enumerate() {
+            if chr.len() > max_panels {
+                return Err(format!(
+                    "Character {} has {} panels, but at most {} are supported",
+                    char_index,
+                    chr.len(),
+                    max_panels
+                ));
+            }
+            if let Some(panel_index) = chr.iter().position(|panel| panel.len() > 64) {
+                return Err(format!(
+                    "Panel {} of character {} has more than 64 pixels",
+                    panel_index, char_index
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Logo {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+/// A rectangle in pixel coordinates of a rendered logo
+#[derive(Debug, Serialize, Copy, Clone, Eq, PartialEq)]
+pub struct Bounds {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+/// The pixels of a rendered logo, as rows of colors from the top
+#[derive(Debug, Serialize)]
+pub struct Pixels {
+    width: usize,
+    height: usize,
+    /// `#rrggbb`, `#rrggbbaa` for partly transparent pixels, or `None` for transparent ones
+    pixels: Vec<Vec<Option<String>>>,
+}
+
+/// Colors in the palette of `/api/v1/logo/dominant`
+const PALETTE_SIZE: usize = 5;
+
+/// A color of the logo and how much of it has that color
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct PaletteColor {
+    /// `#rrggbb`
+    color: String,
+    red: u8,
+    green: u8,
+    blue: u8,
+    /// The fraction of the visible pixels that have this color
+    share: f64,
+}
+
+/// The most used color of a rendered logo, and the ones after it
+#[derive(Debug, Serialize)]
+pub struct Dominant {
+    /// Left out when every pixel is transparent
+    #[serde(flatten)]
+    dominant: Option<PaletteColor>,
+    palette: Vec<PaletteColor>,
+}
+
+fn default_threshold() -> u8 {
+    128
+}
+
+#[derive(Debug, Deserialize, Copy, Clone)]
+pub struct MonoOptions {
+    /// Pixels darker than this are set in the bitmap
+    #[serde(default = "default_threshold")]
+    threshold: u8,
+}
+
+/// A 1-bit-per-pixel bitmap of a rendered logo.
+///
+/// Rows are stored top to bottom, each padded to `stride` bytes. The pixels of a row are packed
+/// left to right starting at the most significant bit of each byte, and a set bit is a dark pixel.
+pub struct MonoBitmap {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub data: Vec<u8>,
+}
+
+impl Logo {
+    /// The tight bounding box of all non-transparent pixels, or `None` if every pixel is
+    /// transparent.
+    fn bounds(&self) -> Option<Bounds> {
+        let (mut min_x, mut min_y) = (self.width, self.height);
+        let (mut max_x, mut max_y) = (0, 0);
+        let mut empty = true;
+
+        for (pixel_index, pixel) in self.data.chunks(4).enumerate() {
+            if pixel[3] == 0 {
+                continue;
+            }
+            let x = pixel_index % self.width;
+            let y = pixel_index / self.width;
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+            empty = false;
+        }
+
+        if empty {
+            return None;
+        }
+
+        Some(Bounds {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1,
+        })
+    }
+
+    /// Scales the logo down by `factor`, averaging a window of `2 * factor` pixels centered on
+    /// each output pixel so that hard edges get softened. With `linear` the colors are averaged
+    /// as light, instead of as sRGB values which darkens the edges.
+    fn downsample(&self, factor: usize, linear: bool) -> Logo {
+        let width = self.width / factor;
+        let height = self.height / factor;
+        let mut data = vec![0; width * height * 4];
+
+        for y in 0..height {
+            let src_y0 = (y * factor).saturating_sub(factor / 2);
+            let src_y1 = (y * factor + factor + factor / 2).min(self.height);
+            for x in 0..width {
+                let src_x0 = (x * factor).saturating_sub(factor / 2);
+                let src_x1 = (x * factor + factor + factor / 2).min(self.width);
+
+                // Colors are weighted by alpha, so transparent pixels don't darken the edges
+                let mut sum = [0f64; 4];
+                for src_y in src_y0..src_y1 {
+                    for src_x in src_x0..src_x1 {
+                        let idx = (src_x + src_y * self.width) * 4;
+                        let alpha = f64::from(self.data[idx + 3]);
+                        for (total, &channel) in sum.iter_mut().zip(&self.data[idx..idx + 3]) {
+                            *total += channel_value(channel, linear) * alpha;
+                        }
+                        sum[3] += alpha;
+                    }
+                }
+                let count = ((src_x1 - src_x0) * (src_y1 - src_y0)) as f64;
+
+                let idx = (x + y * width) * 4;
+                data[idx + 3] = (sum[3] / count) as u8;
+                if sum[3] == 0.0 {
+                    continue;
+                }
+                for (channel, total) in data[idx..idx + 3].iter_mut().zip(&sum) {
+                    *channel = if linear {
+                        from_linear(total / sum[3])
+                    } else {
+                        (total / sum[3]) as u8
+                    };
+                }
+            }
+        }
+
+        Logo {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Transforms the color of every pixel with the preset
+    fn apply_preset(&mut self, preset: &Preset) {
+        for pixel in self.data.chunks_mut(4) {
+            let rgb = [
+                f32::from(pixel[0]),
+                f32::from(pixel[1]),
+                f32::from(pixel[2]),
+            ];
+            for (channel, (row, offset)) in preset.matrix.iter().zip(&preset.offset).enumerate() {
+                let value = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2] + offset;
+                pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    /// Mirrors the logo left to right, top to bottom or both
+    fn flip(&mut self, flip: Flip) {
+        let row_len = self.width * 4;
+        if flip == Flip::H || flip == Flip::Hv {
+            for row in self.data.chunks_mut(row_len) {
+                for x in 0..self.width / 2 {
+                    let mirrored = self.width - 1 - x;
+                    for channel in 0..4 {
+                        row.swap(x * 4 + channel, mirrored * 4 + channel);
+                    }
+                }
+            }
+        }
+        if flip == Flip::V || flip == Flip::Hv {
+            for y in 0..self.height / 2 {
+                let (top, bottom) = self.data.split_at_mut((self.height - 1 - y) * row_len);
+                top[y * row_len..(y + 1) * row_len].swap_with_slice(&mut bottom[..row_len]);
+            }
+        }
+    }
+
+    /// Gives every visible pixel the color of `tint`, keeping its alpha
+    fn tint(&mut self, tint: Color) {
+        for pixel in self.data.chunks_mut(4) {
+            if pixel[3] != 0 {
+                pixel[..3].copy_from_slice(&tint.0[..3]);
+            }
+        }
+    }
+
+    /// The color of every pixel, leaving out the transparent ones
+    fn to_pixels(&self) -> Pixels {
+        let pixels = self
+            .data
+            .chunks(self.width * 4)
+            .map(|row| {
+                row.chunks(4)
+                    .map(|pixel| {
+                        Some(pixel).filter(|pixel| pixel[3] != 0).map(|pixel| {
+                            Color([pixel[0], pixel[1], pixel[2], pixel[3]]).to_string()
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Pixels {
+            width: self.width,
+            height: self.height,
+            pixels,
+        }
+    }
+
+    /// The most used colors of the visible pixels, ignoring how transparent they are
+    fn dominant(&self) -> Dominant {
+        let mut counts = HashMap::new();
+        for pixel in self.data.chunks(4).filter(|pixel| pixel[3] != 0) {
+            *counts
+                .entry([pixel[0], pixel[1], pixel[2]])
+                .or_insert(0usize) += 1;
+        }
+        let visible: usize = counts.values().sum();
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        // Ties go to the lowest color, so the answer doesn't change between renders
+        counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+        let palette: Vec<_> = counts
+            .into_iter()
+            .take(PALETTE_SIZE)
+            .map(|([red, green, blue], count)| PaletteColor {
+                color: Color([red, green, blue, 255]).to_string(),
+                red,
+                green,
+                blue,
+                share: count as f64 / visible as f64,
+            })
+            .collect();
+        Dominant {
+            dominant: palette.first().cloned(),
+            palette,
+        }
+    }
+
+    /// Thresholds the luminance of each pixel as if the logo was drawn on white
+    fn to_mono(&self, threshold: u8) -> MonoBitmap {
+        let stride = self.width.div_ceil(8);
+        let mut data = vec![0; stride * self.height];
+
+        for (pixel_index, pixel) in self.data.chunks(4).enumerate() {
+            let luma = 0.299 * f32::from(pixel[0])
+                + 0.587 * f32::from(pixel[1])
+                + 0.114 * f32::from(pixel[2]);
+            let alpha = f32::from(pixel[3]) / 255.0;
+            let luminance = luma * alpha + 255.0 * (1.0 - alpha);
+
+            if luminance < f32::from(threshold) {
+                let x = pixel_index % self.width;
+                let y = pixel_index / self.width;
+                data[y * stride + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+
+        MonoBitmap {
+            width: self.width,
+            height: self.height,
+            stride,
+            data,
+        }
+    }
+
+    /// Scales the logo to fit in a `side` by `side` square, `margin` pixels in from every edge,
+    /// centered on `background`
+    fn fit_square(&self, side: usize, margin: usize, background: [u8; 4], linear: bool) -> Logo {
+        let inner = side.saturating_sub(margin * 2).max(1);
+        let scale = (inner as f64 / self.width as f64).min(inner as f64 / self.height as f64);
+        let width = ((self.width as f64 * scale).round() as usize)
+            .max(1)
+            .min(inner);
+        let height = ((self.height as f64 * scale).round() as usize)
+            .max(1)
+            .min(inner);
+        let left = (side - width) / 2;
+        let top = (side - height) / 2;
+
+        let mut data = background
+            .iter()
+            .cycle()
+            .take(side * side * 4)
+            .cloned()
+            .collect::<Vec<u8>>();
+
+        let scaled = self.resize(width, height, linear);
+        for y in 0..height {
+            for x in 0..width {
+                let src = (y * width + x) * 4;
+                let mut pixel = [0; 4];
+                pixel.copy_from_slice(&scaled.data[src..src + 4]);
+                let idx = ((top + y) * side + left + x) * 4;
+                blend_over(&mut data[idx..idx + 4], pixel);
+            }
+        }
+
+        Logo {
+            width: side,
+            height: side,
+            data,
+        }
+    }
+
+    /// Fills a canvas of `tile` with copies of the logo from the top left corner, `gap` pixels
+    /// apart. The copies at the right and bottom edges are cut off where the canvas ends.
+    fn tile(&self, tile: Tile, gap: usize) -> Logo {
+        let (width, height) = (tile.width as usize, tile.height as usize);
+        let mut data = vec![0; width * height * 4];
+        for y in (0..height).step_by(self.height + gap) {
+            for x in (0..width).step_by(self.width + gap) {
+                let columns = self.width.min(width - x);
+                for row in 0..self.height.min(height - y) {
+                    let src = row * self.width * 4;
+                    let dst = ((y + row) * width + x) * 4;
+                    data[dst..dst + columns * 4]
+                        .copy_from_slice(&self.data[src..src + columns * 4]);
+                }
+            }
+        }
+
+        Logo {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Draws the logo over a blurred copy of its alpha in the shadow color, on a canvas that is
+    /// large enough for the whole shadow
+    fn with_shadow(&self, shadow: Shadow) -> Logo {
+        let blur = shadow.blur as usize;
+        // The blur spreads `blur` pixels to every side of the shadow, which is offset by `blur`
+        let width = self.width + blur * 3;
+        let height = self.height + blur * 3;
+
+        let mut alpha = vec![0.0; width * height];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                alpha[(y + blur * 2) * width + x + blur * 2] =
+                    f64::from(self.data[(y * self.width + x) * 4 + 3]);
+            }
+        }
+        let alpha = gaussian_blur(&alpha, width, height, blur);
+
+        let [r, g, b, a] = shadow.color.0;
+        let mut data = Vec::with_capacity(width * height * 4);
+        for &alpha in &alpha {
+            let alpha = alpha * f64::from(a) / 255.0;
+            data.extend_from_slice(&[r, g, b, alpha.round().min(255.0) as u8]);
+        }
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let src = (y * self.width + x) * 4;
+                let mut pixel = [0; 4];
+                pixel.copy_from_slice(&self.data[src..src + 4]);
+                let idx = ((y + blur) * width + x + blur) * 4;
+                blend_over(&mut data[idx..idx + 4], pixel);
+            }
+        }
+
+        Logo {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Scales the logo to `width` by `height`, averaging the pixels each output pixel covers
+    fn resize(&self, width: usize, height: usize, linear: bool) -> Logo {
+        let mut data = vec![0; width * height * 4];
+        let x_step = self.width as f64 / width as f64;
+        let y_step = self.height as f64 / height as f64;
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = self.area_average(
+                    x as f64 * x_step,
+                    (x + 1) as f64 * x_step,
+                    y as f64 * y_step,
+                    (y + 1) as f64 * y_step,
+                    linear,
+                );
+                let idx = (y * width + x) * 4;
+                data[idx..idx + 4].copy_from_slice(&pixel);
+            }
+        }
+
+        Logo {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Averages the pixels covered by a rectangle in pixel coordinates, weighting each by how
+    /// much of it is covered and by its alpha, and averaging as light with `linear`
+    fn area_average(&self, x0: f64, x1: f64, y0: f64, y1: f64, linear: bool) -> [u8; 4] {
+        let mut sum = [0f64; 4];
+        let mut area = 0.0;
+        for src_y in y0.floor() as usize..(y1.ceil() as usize).min(self.height) {
+            let coverage_y = y1.min(src_y as f64 + 1.0) - y0.max(src_y as f64);
+            for src_x in x0.floor() as usize..(x1.ceil() as usize).min(self.width) {
+                let coverage = coverage_y * (x1.min(src_x as f64 + 1.0) - x0.max(src_x as f64));
+                let idx = (src_x + src_y * self.width) * 4;
+                let alpha = f64::from(self.data[idx + 3]) * coverage;
+                for (total, &channel) in sum.iter_mut().zip(&self.data[idx..idx + 3]) {
+                    *total += channel_value(channel, linear) * alpha;
+                }
+                sum[3] += alpha;
+                area += coverage;
+            }
+        }
+
+        if sum[3] == 0.0 {
+            return [0; 4];
+        }
+        let color = |total: f64| {
+            if linear {
+                from_linear(total / sum[3])
+            } else {
+                (total / sum[3]).round() as u8
+            }
+        };
+        [
+            color(sum[0]),
+            color(sum[1]),
+            color(sum[2]),
+            (sum[3] / area).round() as u8,
+        ]
+    }
+
+    /// Crops the logo to its bounds, leaving `padding` transparent pixels on every side.
+    fn trim(self, padding: usize) -> Logo {
+        let bounds = match self.bounds() {
+            Some(bounds) => bounds,
+            None => return self,
+        };
+
+        let width = bounds.width + padding * 2;
+        let height = bounds.height + padding * 2;
+        let mut data = vec![0; width * height * 4];
+
+        for row in 0..bounds.height {
+            let src_start = ((bounds.y + row) * self.width + bounds.x) * 4;
+            let dst_start = ((padding + row) * width + padding) * 4;
+            let len = bounds.width * 4;
+            data[dst_start..dst_start + len]
+                .copy_from_slice(&self.data[src_start..src_start + len]);
+        }
+
+        Logo {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+/// Where the changes of the live logo go
+pub trait Publisher {
+    /// Sends a change to the clients that follow the live logo
+    fn send_update(&self, config: &Config, logo_png: &[u8]);
+    /// Stores a change in the history
+    fn save_logo(
+        &self,
+        config: &Config,
+        logo_png: &[u8],
+        thumbnail: Option<&[u8]>,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// Sends the changes to the websocket and MJPEG clients, and stores them in the database
+pub struct LivePublisher;
+
+impl Publisher for LivePublisher {
+    fn send_update(&self, config: &Config, logo_png: &[u8]) {
+        if config.enable_websocket {
+            live::send_update(config, logo_png);
+        }
+        mjpeg::send_update(config);
+    }
+
+    fn save_logo(
+        &self,
+        config: &Config,
+        logo_png: &[u8],
+        thumbnail: Option<&[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(db::save_logo(config, logo_png, thumbnail)?)
+    }
+}
+
+pub fn update_logo(
+    config: &Config,
+    fetcher: &dyn Fetcher,
+    publisher: &dyn Publisher,
+) -> Result<(), Box<dyn Error>> {
+    let changed = fetch_logo(config, fetcher)?;
+    if let Some(ref logo_png) = changed {
+        publisher.send_update(config, logo_png);
+    }
+
+    // Every change is sent live, but a change that is held back is only stored on a later poll
+    let interval = Duration::from_secs(config.history_interval);
+    let due = HISTORY_WRITES.lock().take_due(changed, interval);
+    if let Some(logo_png) = due {
+        // The pending change is always the live logo, so its thumbnail is the live one
+        let thumbnail = match config.thumbnail_width {
+            0 => None,
+            width => match get_logo_thumbnail(config, width as usize) {
+                Ok(thumbnail) => Some(thumbnail),
+                Err(err) => {
+                    eprintln!("Error generating thumbnail: {}", err);
+                    None
+                }
+            },
+        };
+        if let Err(err) = publisher.save_logo(config, &logo_png, thumbnail.as_deref()) {
+            eprintln!("Error saving logo to db: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetches the live logo into the cache, and returns it as a PNG if it changed
+fn fetch_logo(config: &Config, fetcher: &dyn Fetcher) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let live_logo = match metrics::UPSTREAM_FETCH.time(|| fetcher.fetch())? {
+        Some(live_logo) => live_logo,
+        None => return Ok(None),
+    };
+
+    let old_logo = LOGO_CACHE.read();
+
+    if live_logo != *old_logo {
+        // Avoid deadlock
+        drop(old_logo);
+
+        // Logged once per logo, rather than on every render of it
+        if live_logo.logo.len() > MAX_PANELS.len() {
+            match config.oversized_logo {
+                OversizedLogo::Truncate => eprintln!(
+                    "The upstream sent {} characters, leaving out the {} that don't fit",
+                    live_logo.logo.len(),
+                    live_logo.logo.len() - MAX_PANELS.len()
+                ),
+                OversizedLogo::Error => eprintln!(
+                    "The upstream sent {} characters, which don't fit, so the logo can't be rendered",
+                    live_logo.logo.len()
+                ),
+            }
+        }
+
+        let mut logo_cache = LOGO_CACHE.write();
+        *PRERENDERED.write() = None;
+        let previous_logo = mem::replace(&mut *logo_cache, live_logo);
+        if !previous_logo.logo.is_empty() {
+            *PREVIOUS_LOGO.write() = Some((previous_logo, Instant::now()));
+        }
+        *LOGO_CHANGED_AT.write() = Some(Utc::now());
+
+        // Avoid deadlock
+        drop(logo_cache);
+
+        if let Err(err) = prerender(config) {
+            eprintln!("Error rendering the common sizes of the logo: {}", err);
+        }
+        return Ok(Some(get_logo_png(config, &LogoOptions::default())?));
+    }
+
+    Ok(None)
+}
+
+/// A zip of favicons, apple touch and PWA icons of the live logo, with a `site.webmanifest` for
+/// the PWA icons
+pub fn get_icon_set(config: &Config, options: &IconOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    let live_logo = LOGO_CACHE.read();
+    if let Some(ref icon_set) = *ICON_SET.read() {
+        if icon_set.logo == *live_logo
+            && icon_set.srgb == config.png_srgb
+            && icon_set.options == *options
+        {
+            return Ok(icon_set.zip.clone());
+        }
+    }
+
+    let logo = get_logo_data(
+        config,
+        &live_logo,
+        &LogoOptions {
+            size: Some(ICON_LOGO_SIZE),
+            trim: true,
+            ..LogoOptions::default()
+        },
+    )?;
+    let background = match options.background {
+        Some(ref background) => background.parse::<Color>()?.0,
+        None => [0; 4],
+    };
+    // What the background looks like on white, for the icons that can't be transparent
+    let mut opaque_background = [255; 4];
+    blend_over(&mut opaque_background, background);
+
+    let mut files = vec![];
+    for &(name, side, margin) in &ICONS {
+        let background = match name {
+            "apple-touch-icon.png" => opaque_background,
+            _ => background,
+        };
+        let icon = logo.fit_square(side, side * margin / 100, background, config.linear_scaling);
+        files.push((name.to_owned(), encode_png(&icon, config.png_srgb)?));
+    }
+    let favicon = logo.fit_square(48, 0, background, config.linear_scaling);
+    files.push((
+        "favicon.ico".to_owned(),
+        ico::encode(favicon.width, favicon.height, &favicon.data)?,
+    ));
+    files.push((
+        "site.webmanifest".to_owned(),
+        web_manifest(options, &logo, Color(opaque_background))?,
+    ));
+
+    let files: Vec<(&str, &[u8])> = files
+        .iter()
+        .map(|(name, data)| (name.as_str(), data.as_slice()))
+        .collect();
+    let zip = zip::write(&files);
+    *ICON_SET.write() = Some(IconSet {
+        logo: live_logo.clone(),
+        srgb: config.png_srgb,
+        options: options.clone(),
+        zip: zip.clone(),
+    });
+    Ok(zip)
+}
+
+/// A web manifest listing the PWA icons, themed in the most used color of the logo
+fn web_manifest(
+    options: &IconOptions,
+    logo: &Logo,
+    background: Color,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    #[derive(Serialize)]
+    struct Icon {
+        src: String,
+        sizes: String,
+        #[serde(rename = "type")]
+        content_type: &'static str,
+    }
+
+    #[derive(Serialize)]
+    struct Manifest<'a> {
+        name: &'a str,
+        short_name: &'a str,
+        icons: Vec<Icon>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        theme_color: Option<String>,
+        background_color: String,
+        display: &'static str,
+    }
+
+    let name = options.name.as_deref().unwrap_or("Logo");
+    let icons = ICONS
+        .iter()
+        .filter(|(name, _, _)| name.starts_with("android-chrome-"))
+        .map(|&(name, side, _)| Icon {
+            src: format!("/{}", name),
+            sizes: format!("{0}x{0}", side),
+            content_type: "image/png",
+        })
+        .collect();
+    let manifest = Manifest {
+        name,
+        short_name: name,
+        icons,
+        theme_color: logo.dominant().dominant.map(|dominant| dominant.color),
+        background_color: background.to_string(),
+        display: "standalone",
+    };
+    Ok(serde_json::to_vec_pretty(&manifest)?)
+}
+
+/// Renders the live logo at the common sizes, so requests for them don't have to
+fn prerender(config: &Config) -> Result<(), Box<dyn Error>> {
+    let live_logo = LOGO_CACHE.read();
+    let images = PRERENDERED_SIZES
+        .iter()
+        .map(|&size| {
+            let options = LogoOptions {
+                size: Some(size),
+                ..LogoOptions::default()
+            };
+            Ok((size, render_image(config, &live_logo, &options)?))
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+    *PRERENDERED.write() = Some(Prerendered {
+        generation: config.generation,
+        images,
+    });
+    Ok(())
+}
+
+/// The live logo rendered ahead of time with the options, if it has been
+fn get_prerendered(config: &Config, options: &LogoOptions) -> Option<Vec<u8>> {
+    let size = options.size.unwrap_or(1);
+    // Whether the format is PNG because it was asked for or by default makes no difference
+    let default_options = LogoOptions {
+        size: options.size,
+        format: options.format,
+        ..LogoOptions::default()
+    };
+    if *options != default_options || options.format() != Format::Png {
+        return None;
+    }
+    let prerendered = PRERENDERED.read();
+    let prerendered = prerendered
+        .as_ref()
+        .filter(|prerendered| prerendered.generation == config.generation)?;
+    prerendered
+        .images
+        .iter()
+        .find(|&&(prerendered_size, _)| prerendered_size == size)
+        .map(|(_, image)| image.clone())
+}
+
+/// Notes that the poller is alive, after each poll and when it starts
+pub fn record_poll() {
+    *LAST_POLL.write() = Instant::now();
+}
+
+/// Whether the poller has gone too long without finishing a poll, so the live logo may be stale
+pub fn poller_stalled(config: &Config) -> bool {
+    LAST_POLL.read().elapsed() > Duration::from_secs(config.poll_interval * 3) + POLL_STALL_GRACE
+}
+
+/// Whether the live logo has been fetched since startup
+pub fn is_ready() -> bool {
+    !LOGO_CACHE.read().logo.is_empty()
+}
+
+/// When the live logo last changed, which tells the versions of it apart
+pub fn changed_at() -> Option<DateTime<Utc>> {
+    *LOGO_CHANGED_AT.read()
+}
+
+/// Renders the live logo in the format given in the options
+pub fn get_logo_image(config: &Config, options: &LogoOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(image) = get_prerendered(config, options) {
+        return Ok(image);
+    }
+    // A reload swaps in another config, which can render the same options differently
+    let key = format!("{:?} {:?} {}", changed_at(), options, config.generation);
+    RENDERS.run(key, || render_image(config, &LOGO_CACHE.read(), options))
+}
+
+/// An image, and the name of the encoding that made it small enough
+type FittedImage = (Vec<u8>, &'static str);
+
+/// Renders the live logo in at most `max_bytes`, trying PNGs that are slower to compress or
+/// indexed when the usual one is too large. Gives the image and the name of the encoding that was
+/// used, or `None` if none of them fit.
+pub fn get_logo_image_within_bytes(
+    config: &Config,
+    options: &LogoOptions,
+    max_bytes: usize,
+) -> Result<Option<FittedImage>, Box<dyn Error>> {
+    let image = get_logo_image(config, options)?;
+    if image.len() <= max_bytes {
+        return Ok(Some((image, options.format().name())));
+    }
+    if options.format() != Format::Png {
+        return Ok(None);
+    }
+
+    let logo = get_logo_data(config, &LOGO_CACHE.read(), options)?;
+    let best = encode_png_with(
+        &logo,
+        config.png_srgb,
+        options.bit_depth(),
+        png::Compression::Best,
+    )?;
+    if best.len() <= max_bytes {
+        return Ok(Some((best, "png-best")));
+    }
+    match encode_palette_png(&logo, config.png_srgb)? {
+        Some(palette) if palette.len() <= max_bytes => Ok(Some((palette, "png-palette"))),
+        _ => Ok(None),
+    }
+}
+
+/// Renders any logo in the format given in the options
+pub fn render_image(
+    config: &Config,
+    live_logo: &LogoResponse,
+    options: &LogoOptions,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    render_image_within(config, live_logo, options, Budget::unlimited())
+}
+
+/// Renders any logo, failing with `budget::Exceeded` if it runs out of the budget, which is
+/// checked between drawing and encoding the image
+pub fn render_image_within(
+    config: &Config,
+    live_logo: &LogoResponse,
+    options: &LogoOptions,
+    budget: Budget,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let logo = get_logo_data(config, live_logo, options)?;
+    budget.check()?;
+
+    match options.format() {
+        Format::Png => encode_png_with_depth(&logo, config.png_srgb, options.bit_depth()),
+        Format::Ico => ico::encode(logo.width, logo.height, &logo.data),
+        Format::Jpeg => {
+            let background = match options.jpeg_bg {
+                Some(ref jpeg_bg) => jpeg_bg.parse()?,
+                None => config.jpeg_background,
+            };
+            encode_jpeg(&logo, background)
+        }
+    }
+}
+
+/// Renders a fade from the previous logo to the live one as an APNG, if `options.animate` is set
+/// and the logo changed in the last `ANIMATION_WINDOW`. Viewers without APNG support show the live
+/// logo.
+pub fn get_logo_animation(
+    config: &Config,
+    options: &LogoOptions,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    if !options.animate
+        || options.format() != Format::Png
+        || options.bit_depth() != png::BitDepth::Eight
+    {
+        return Ok(None);
+    }
+    let previous = match *PREVIOUS_LOGO.read() {
+        Some((ref previous_logo, changed)) if changed.elapsed() < ANIMATION_WINDOW => {
+            get_logo_data(config, previous_logo, options)?
+        }
+        _ => return Ok(None),
+    };
+    let current = get_logo_data(config, &LOGO_CACHE.read(), options)?;
+    // Options like `trim` can give the two logos different sizes
+    if (previous.width, previous.height) != (current.width, current.height) {
+        return Ok(None);
+    }
+
+    let frames: Vec<Logo> = (0..ANIMATION_FRAMES)
+        .map(|frame| {
+            let amount = frame as f64 / (ANIMATION_FRAMES - 1) as f64;
+            let data = previous
+                .data
+                .iter()
+                .zip(&current.data)
+                .map(|(&from, &to)| {
+                    (f64::from(from) + (f64::from(to) - f64::from(from)) * amount).round() as u8
+                })
+                .collect();
+            Logo {
+                width: current.width,
+                height: current.height,
+                data,
+            }
+        })
+        .collect();
+    encode_apng(&current, &frames, config.png_srgb).map(Some)
+}
+
+pub fn get_logo_png(config: &Config, options: &LogoOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    encode_png(
+        &get_logo_data(config, &LOGO_CACHE.read(), options)?,
+        config.png_srgb,
+    )
+}
+
+/// The live logo as a PNG scaled down to `width`, for the history
+fn get_logo_thumbnail(config: &Config, width: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let live_logo = LOGO_CACHE.read();
+    check_fits(config, &live_logo)?;
+    let logo = render_logo(&live_logo, &LogoOptions::default())?;
+    let height = (logo.height * width / logo.width).max(1);
+    encode_png(
+        &logo.resize(width, height, config.linear_scaling),
+        config.png_srgb,
+    )
+}
+
+/// Which characters look different in two PNGs of the full logo, as a bitmask where bit `n` is
+/// character `n`. Images of different sizes differ in every character.
+pub fn changed_characters(old_png: &[u8], new_png: &[u8]) -> Result<i16, Box<dyn Error>> {
+    let (old, new) = (decode_png(old_png)?, decode_png(new_png)?);
+    if (old.width, old.height) != (new.width, new.height) || new.width % 152 != 0 {
+        return Ok(ALL_CHARACTERS);
+    }
+    let pixel_size = new.width / 152;
+
+    let mut changed = 0;
+    for char_index in 0..MAX_PANELS.len() {
+        let (x, width) = character_columns(char_index);
+        let columns = x * pixel_size * 4..(x + width) * pixel_size * 4;
+        let row_differs = |y: usize| {
+            let row = y * new.width * 4;
+            let columns = row + columns.start..row + columns.end;
+            old.data[columns.clone()] != new.data[columns]
+        };
+        if (0..new.height).any(row_differs) {
+            changed |= 1 << char_index;
+        }
+    }
+    Ok(changed)
+}
+
+/// The first column of a character in the full logo at size 1, and how many columns wide it is
+fn character_columns(char_index: usize) -> (usize, usize) {
+    if char_index == 0 {
+        (0, 8)
+    } else {
+        ((char_index * 3 - 2) * 8, 24)
+    }
+}
+
+/// How the live logo differs from an earlier version of it
+#[derive(Debug, Serialize)]
+pub struct Delta {
+    /// Bitmask of the characters that look different, where bit n is character n
+    changed_characters: i16,
+    /// Pixels of the full logo at size 1 that are different
+    changed_pixels: usize,
+    /// Pixels of the full logo at size 1
+    pixels: usize,
+    /// Pixels that are different in each character
+    changed_pixels_by_character: Vec<usize>,
+}
+
+/// Compares the live logo with a PNG of the full logo, like the ones stored in the history
+pub fn get_logo_delta(config: &Config, old_png: &[u8]) -> Result<Delta, Box<dyn Error>> {
+    let old = decode_png(old_png)?;
+    let new = get_logo_data(config, &LOGO_CACHE.read(), &LogoOptions::default())?;
+    let pixels = new.width * new.height;
+    // Logos of different sizes can't be compared pixel by pixel
+    if (old.width, old.height) != (new.width, new.height) {
+        return Ok(Delta {
+            changed_characters: ALL_CHARACTERS,
+            changed_pixels: pixels,
+            pixels,
+            changed_pixels_by_character: (0..MAX_PANELS.len())
+                .map(|char_index| character_columns(char_index).1 * new.height)
+                .collect(),
+        });
+    }
+
+    let mut changed_pixels_by_character = vec![0; MAX_PANELS.len()];
+    let mut changed_pixels = 0;
+    for (pixel_index, (old_pixel, new_pixel)) in
+        old.data.chunks(4).zip(new.data.chunks(4)).enumerate()
+    {
+        if old_pixel == new_pixel {
+            continue;
+        }
+        changed_pixels += 1;
+        let x = pixel_index % new.width;
+        if let Some(char_index) = (0..MAX_PANELS.len()).find(|&char_index| {
+            let (start, width) = character_columns(char_index);
+            (start..start + width).contains(&x)
+        }) {
+            changed_pixels_by_character[char_index] += 1;
+        }
+    }
+    let changed_characters = changed_pixels_by_character
+        .iter()
+        .enumerate()
+        .filter(|&(_, &changed)| changed > 0)
+        .fold(0, |mask, (char_index, _)| mask | 1 << char_index);
+
+    Ok(Delta {
+        changed_characters,
+        changed_pixels,
+        pixels,
+        changed_pixels_by_character,
+    })
+}
+
+/// A Lottie animation from a PNG of the full logo, like the ones stored in the history, to the
+/// live logo
+pub fn get_logo_transition(
+    config: &Config,
+    old_png: &[u8],
+    duration_ms: u32,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let old = decode_png(old_png)?;
+    let new = get_logo_data(config, &LOGO_CACHE.read(), &LogoOptions::default())?;
+    // Pixels outside of a logo of another size count as transparent
+    let pixel = |logo: &Logo, x: usize, y: usize| {
+        if x < logo.width && y < logo.height {
+            let index = (y * logo.width + x) * 4;
+            let mut pixel = [0; 4];
+            pixel.copy_from_slice(&logo.data[index..index + 4]);
+            pixel
+        } else {
+            [0; 4]
+        }
+    };
+
+    let (width, height) = (new.width.max(old.width), new.height.max(old.height));
+    let mut changes = vec![];
+    for y in 0..height {
+        for x in 0..width {
+            let (from, to) = (pixel(&old, x, y), pixel(&new, x, y));
+            if from != to {
+                changes.push(lottie::PixelChange { x, y, from, to });
+            }
+        }
+    }
+    Ok(lottie::transition(width, height, &changes, duration_ms))
+}
+
+/// Color of the text that `stamp_png` writes, which can be read on both light and dark pages
+const STAMP_COLOR: [u8; 4] = [128, 128, 128, 255];
+
+/// Adds a strip below a PNG, like the ones stored in the history, with `text` written in it. The
+/// text is scaled with the full logo, and the image is made wider if the text doesn't fit.
+pub fn stamp_png(png: &[u8], text: &str, srgb: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let logo = decode_png(png)?;
+    let scale = (logo.width / 152).max(1);
+    let margin = 2 * scale;
+    let text_width = font::text_width(text, scale);
+    let width = logo.width.max(text_width + margin * 2);
+    let height = logo.height + font::GLYPH_HEIGHT * scale + margin * 2;
+
+    let mut data = vec![0; width * height * 4];
+    let left = (width - logo.width) / 2;
+    for (y, row) in logo.data.chunks(logo.width * 4).enumerate() {
+        let start = (y * width + left) * 4;
+        data[start..start + row.len()].copy_from_slice(row);
+    }
+    font::draw_text(
+        &mut data,
+        width,
+        ((width - text_width) / 2, logo.height + margin),
+        text,
+        scale,
+        STAMP_COLOR,
+    );
+
+    encode_png(
+        &Logo {
+            width,
+            height,
+            data,
+        },
+        srgb,
+    )
+}
+
+/// How many pixels are different in two PNGs, where images of different sizes differ everywhere
+pub fn changed_pixels(old_png: &[u8], new_png: &[u8]) -> Result<usize, Box<dyn Error>> {
+    let (old, new) = (decode_png(old_png)?, decode_png(new_png)?);
+    if (old.width, old.height) != (new.width, new.height) {
+        return Ok((old.width * old.height).max(new.width * new.height));
+    }
+    Ok(old
+        .data
+        .chunks(4)
+        .zip(new.data.chunks(4))
+        .filter(|(old_pixel, new_pixel)| old_pixel != new_pixel)
+        .count())
+}
+
+/// The colors of the visible pixels of a PNG, as sorted `#rrggbb`
+pub fn image_colors(png: &[u8]) -> Result<Vec<String>, Box<dyn Error>> {
+    let logo = decode_png(png)?;
+    let colors: BTreeSet<String> = logo
+        .data
+        .chunks(4)
+        .filter(|pixel| pixel[3] != 0)
+        .map(|pixel| Color([pixel[0], pixel[1], pixel[2], 255]).to_string())
+        .collect();
+    Ok(colors.into_iter().collect())
+}
+
+fn decode_png(png: &[u8]) -> Result<Logo, Box<dyn Error>> {
+    let (info, mut reader) = png::Decoder::new(png).read_info()?;
+    if info.color_type != png::ColorType::RGBA || info.bit_depth != png::BitDepth::Eight {
+        return Err("Only 8 bit RGBA images can be read".into());
+    }
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data)?;
+    Ok(Logo {
+        width: info.width as usize,
+        height: info.height as usize,
+        data,
+    })
+}
+
+/// Encodes the logo as a PNG, marked as sRGB when `srgb` is set so viewers don't guess its colors
+fn encode_png(logo: &Logo, srgb: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    encode_png_with_depth(logo, srgb, png::BitDepth::Eight)
+}
+
+/// Encodes the logo as a PNG with 8 or 16 bits per channel. The 16 bit channels hold the same
+/// colors, as each 8 bit value is scaled up to the full range.
+fn encode_png_with_depth(
+    logo: &Logo,
+    srgb: bool,
+    depth: png::BitDepth,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    encode_png_with(logo, srgb, depth, png::Compression::Default)
+}
+
+fn encode_png_with(
+    logo: &Logo,
+    srgb: bool,
+    depth: png::BitDepth,
+    compression: png::Compression,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let start = Instant::now();
+    let mut result = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(&mut result, logo.width as u32, logo.height as u32); // Width is 2 pixels and height is 1.
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(depth);
+        // Pin the encoding so the same pixels always give the same bytes, which keeps ETags
+        // stable across restarts and upgrades. No time or text chunks are written.
+        encoder.set_compression(compression);
+        encoder.set_filter(png::FilterType::Sub);
+        let mut writer = encoder.write_header().unwrap();
+
+        if srgb {
+            // Perceptual rendering intent, and the gamma of sRGB for viewers that don't know sRGB
+            writer.write_chunk(*b"sRGB", &[0])?;
+            writer.write_chunk(*b"gAMA", &45455u32.to_be_bytes())?;
+        }
+        if depth == png::BitDepth::Sixteen {
+            // Big-endian, and 255 * 257 is 65535
+            let data: Vec<u8> = logo
+                .data
+                .iter()
+                .flat_map(|&channel| (u16::from(channel) * 257).to_be_bytes())
+                .collect();
+            writer.write_image_data(&data)?;
+        } else {
+            writer.write_image_data(&logo.data).unwrap(); // Save
+        }
+    }
+
+    metrics::PNG_ENCODE.observe(start.elapsed());
+    Ok(result)
+}
+
+/// Encodes the logo as a PNG of indices into a palette of its colors, with as few bits per pixel
+/// as the palette needs, or gives `None` if the logo has more than 256 colors
+fn encode_palette_png(logo: &Logo, srgb: bool) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut palette: Vec<[u8; 4]> = vec![];
+    let mut indices = HashMap::new();
+    let mut pixels = Vec::with_capacity(logo.width * logo.height);
+    for pixel in logo.data.chunks(4) {
+        let color = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        let index = match indices.get(&color) {
+            Some(&index) => index,
+            None if palette.len() == 256 => return Ok(None),
+            None => {
+                let index = palette.len() as u8;
+                indices.insert(color, index);
+                palette.push(color);
+                index
+            }
+        };
+        pixels.push(index);
+    }
+
+    let depth = match palette.len() {
+        0..=2 => png::BitDepth::One,
+        3..=4 => png::BitDepth::Two,
+        5..=16 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    };
+    // Rows start on a whole byte, with the first pixel in the most significant bits
+    let bits = depth as usize;
+    let stride = (logo.width * bits).div_ceil(8);
+    let mut data = vec![0; stride * logo.height];
+    for (pixel_index, &index) in pixels.iter().enumerate() {
+        let bit = (pixel_index % logo.width) * bits;
+        data[pixel_index / logo.width * stride + bit / 8] |= index << (8 - bits - bit % 8);
+    }
+
+    let mut result = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut result, logo.width as u32, logo.height as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(depth);
+        encoder.set_compression(png::Compression::Best);
+        encoder.set_filter(png::FilterType::NoFilter);
+        let mut writer = encoder.write_header()?;
+
+        if srgb {
+            writer.write_chunk(*b"sRGB", &[0])?;
+            writer.write_chunk(*b"gAMA", &45455u32.to_be_bytes())?;
+        }
+        let colors: Vec<u8> = palette
+            .iter()
+            .flat_map(|color| color[..3].to_vec())
+            .collect();
+        writer.write_chunk(*b"PLTE", &colors)?;
+        // The alpha of every color up to the last one that isn't opaque
+        if let Some(last) = palette.iter().rposition(|color| color[3] != 255) {
+            let alphas: Vec<u8> = palette[..=last].iter().map(|color| color[3]).collect();
+            writer.write_chunk(*b"tRNS", &alphas)?;
+        }
+        writer.write_image_data(&data)?;
+    }
+
+    Ok(Some(result))
 }
 
-#[derive(Debug, Deserialize, Copy, Clone, Default)]
-pub struct LogoOptions {
-    size: Option<u32>,
-    character: Option<usize>,
-    #[serde(default)]
-    crop: bool,
+/// Encodes an APNG that plays `frames` once, where `still` is the image shown by viewers that
+/// don't support APNG. It is not part of the animation, so every frame is stored in fdAT chunks.
+fn encode_apng(still: &Logo, frames: &[Logo], srgb: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut result = Vec::new();
+
+    {
+        let mut encoder = png::Encoder::new(&mut result, still.width as u32, still.height as u32);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Default);
+        encoder.set_filter(png::FilterType::Sub);
+        let mut writer = encoder.write_header()?;
+
+        if srgb {
+            writer.write_chunk(*b"sRGB", &[0])?;
+            writer.write_chunk(*b"gAMA", &45455u32.to_be_bytes())?;
+        }
+        // Number of frames, and how many times to play them
+        let mut actl = (frames.len() as u32).to_be_bytes().to_vec();
+        actl.extend_from_slice(&1u32.to_be_bytes());
+        writer.write_chunk(*b"acTL", &actl)?;
+        writer.write_image_data(&still.data)?;
+
+        // The fcTL and fdAT chunks share one sequence
+        let mut sequence = 0u32;
+        for frame in frames {
+            let mut fctl = sequence.to_be_bytes().to_vec();
+            fctl.extend_from_slice(&(frame.width as u32).to_be_bytes());
+            fctl.extend_from_slice(&(frame.height as u32).to_be_bytes());
+            // The frame covers the whole image
+            fctl.extend_from_slice(&[0; 8]);
+            fctl.extend_from_slice(&ANIMATION_FRAME_DELAY.to_be_bytes());
+            fctl.extend_from_slice(&100u16.to_be_bytes());
+            // Leave the frame in place, and replace what was there with it
+            fctl.extend_from_slice(&[0, 0]);
+            writer.write_chunk(*b"fcTL", &fctl)?;
+
+            // An fdAT holds the same compressed data as the IDAT of a PNG of the frame
+            let mut fdat = (sequence + 1).to_be_bytes().to_vec();
+            fdat.extend(image_data(&encode_png(frame, false)?));
+            writer.write_chunk(*b"fdAT", &fdat)?;
+            sequence += 2;
+        }
+    }
+
+    Ok(result)
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
-pub struct LogoResponse {
-    logo: Vec<Vec<Vec<String>>>,
+/// The compressed image data of a PNG, from all of its IDAT chunks
+fn image_data(png: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    // Skip the signature
+    let mut offset = 8;
+    while offset + 8 <= png.len() {
+        let mut length = [0; 4];
+        length.copy_from_slice(&png[offset..offset + 4]);
+        let length = u32::from_be_bytes(length) as usize;
+        let chunk_data = png.get(offset + 8..offset + 8 + length).unwrap_or_default();
+        if &png[offset + 4..offset + 8] == b"IDAT" {
+            data.extend_from_slice(chunk_data);
+        }
+        // Length, type, data and crc
+        offset += 12 + length;
+    }
+    data
 }
 
-pub struct Logo {
-    width: usize,
-    height: usize,
-    data: Vec<u8>,
+/// Encodes the logo as a JPEG, drawn on `background` as JPEGs can't be transparent
+fn encode_jpeg(logo: &Logo, background: Color) -> Result<Vec<u8>, Box<dyn Error>> {
+    if logo.width > usize::from(u16::MAX) || logo.height > usize::from(u16::MAX) {
+        return Err(format!("{}x{} is too large for a JPEG", logo.width, logo.height).into());
+    }
+
+    let background = background.opaque().0;
+    let mut rgb = Vec::with_capacity(logo.width * logo.height * 3);
+    for pixel in logo.data.chunks(4) {
+        let mut composite = background;
+        blend_over(&mut composite, [pixel[0], pixel[1], pixel[2], pixel[3]]);
+        rgb.extend_from_slice(&composite[..3]);
+    }
+
+    let mut result = Vec::new();
+    jpeg_encoder::Encoder::new(&mut result, JPEG_QUALITY).encode(
+        &rgb,
+        logo.width as u16,
+        logo.height as u16,
+        jpeg_encoder::ColorType::Rgb,
+    )?;
+
+    Ok(result)
 }
 
-pub fn update_logo() -> Result<(), Box<dyn Error>> {
-    let live_logo: LogoResponse = reqwest::get("https://logo-api.g2.iterate.no/logo")?.json()?;
-    let old_logo = LOGO_CACHE.read();
+pub fn get_logo_bounds(
+    config: &Config,
+    options: &LogoOptions,
+) -> Result<Option<Bounds>, Box<dyn Error>> {
+    Ok(get_logo_data(config, &LOGO_CACHE.read(), options)?.bounds())
+}
 
-    if live_logo != *old_logo {
-        // Avoid deadlock
-        drop(old_logo);
+pub fn get_logo_pixels(config: &Config, options: &LogoOptions) -> Result<Pixels, Box<dyn Error>> {
+    Ok(get_logo_data(config, &LOGO_CACHE.read(), options)?.to_pixels())
+}
 
-        let mut logo_cache = LOGO_CACHE.write();
-        drop(mem::replace(&mut *logo_cache, live_logo));
+pub fn get_logo_dominant(
+    config: &Config,
+    options: &LogoOptions,
+) -> Result<Dominant, Box<dyn Error>> {
+    Ok(get_logo_data(config, &LOGO_CACHE.read(), options)?.dominant())
+}
 
-        // Avoid deadlock
-        drop(logo_cache);
+pub fn get_logo_mono(
+    config: &Config,
+    options: &LogoOptions,
+    mono_options: MonoOptions,
+) -> Result<MonoBitmap, Box<dyn Error>> {
+    Ok(get_logo_data(config, &LOGO_CACHE.read(), options)?.to_mono(mono_options.threshold))
+}
+
+/// Fails for a logo with more characters than fit when `oversized_logo` is `error`, as they are
+/// otherwise left out
+fn check_fits(config: &Config, live_logo: &LogoResponse) -> Result<(), Box<dyn Error>> {
+    if config.oversized_logo == OversizedLogo::Error && live_logo.logo.len() > MAX_PANELS.len() {
+        return Err(format!(
+            "The logo has {} characters, but at most {} fit",
+            live_logo.logo.len(),
+            MAX_PANELS.len()
+        )
+        .into());
+    }
+    Ok(())
+}
 
-        let logo_png = get_logo_png(LogoOptions::default())?;
+fn get_logo_data(
+    config: &Config,
+    live_logo: &LogoResponse,
+    options: &LogoOptions,
+) -> Result<Logo, Box<dyn Error>> {
+    check_fits(config, live_logo)?;
+    let rainbow_options;
+    let options = if options.rainbow {
+        rainbow_options = options.with_rainbow(&config.rainbow_palette);
+        &rainbow_options
+    } else {
+        options
+    };
 
-        live::send_update(&logo_png);
-        if let Err(err) = db::save_logo(&logo_png) {
-            eprintln!("Error saving logo to db: {}", err);
-        }
+    let mut logo = if options.smooth {
+        let size = options.size.unwrap_or(1) * SMOOTH_FACTOR;
+        render_logo(
+            live_logo,
+            &LogoOptions {
+                size: Some(size),
+                ..options.clone()
+            },
+        )?
+        .downsample(SMOOTH_FACTOR as usize, config.linear_scaling)
+    } else {
+        render_logo(live_logo, options)?
+    };
+
+    if let Some(flip) = options.flip {
+        logo.flip(flip);
     }
 
-    Ok(())
+    if let Some(ref name) = options.preset {
+        let preset = config
+            .preset(name)
+            .ok_or_else(|| format!("{} is not a known preset", name))?;
+        logo.apply_preset(&preset);
+    }
+
+    if let Some(ref tint) = options.tint {
+        logo.tint(tint.parse()?);
+    }
+
+    if options.trim {
+        logo = logo.trim(options.padding.unwrap_or(0) as usize);
+    }
+
+    if let Some(ref shadow) = options.shadow {
+        logo = logo.with_shadow(shadow.parse()?);
+    }
+
+    if let Some(avatar) = options.avatar {
+        let background = match options.background {
+            Some(ref background) => background.parse::<Color>()?.0,
+            None => [0; 4],
+        };
+        logo = logo.fit_square(avatar as usize, 0, background, config.linear_scaling);
+    }
+
+    if let Some(ref tile) = options.tile {
+        logo = logo.tile(tile.parse()?, options.gap.unwrap_or(0) as usize);
+    }
+
+    Ok(logo)
 }
-pub fn get_logo_png(options: LogoOptions) -> Result<Vec<u8>, Box<dyn Error>> {
-    let mut result = Vec::new();
-    let logo = get_logo_data(options)?; // An array containing a RGBA sequence. First pixel is red and second pixel is black.
 
-    {
-        let mut encoder = png::Encoder::new(&mut result, logo.width as u32, logo.height as u32); // Width is 2 pixels and height is 1.
-        encoder.set_color(png::ColorType::RGBA);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
+/// A color channel as a number to average, as light with `linear`
+fn channel_value(channel: u8, linear: bool) -> f64 {
+    if linear {
+        SRGB_TO_LINEAR[usize::from(channel)]
+    } else {
+        f64::from(channel)
+    }
+}
+
+/// An sRGB channel from an amount of light between 0 and 1
+fn from_linear(value: f64) -> u8 {
+    let srgb = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Blurs a single channel image with a gaussian kernel reaching `radius` pixels out, one direction at
+/// a time. Values outside the image count as 0.
+fn gaussian_blur(values: &[f64], width: usize, height: usize, radius: usize) -> Vec<f64> {
+    // Three standard deviations covers nearly all of the kernel
+    let sigma = radius as f64 / 3.0;
+    let kernel: Vec<f64> = (0..=radius * 2)
+        .map(|i| {
+            let distance = i as f64 - radius as f64;
+            (-distance * distance / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let total: f64 = kernel.iter().sum();
+    let kernel: Vec<f64> = kernel.iter().map(|weight| weight / total).collect();
+
+    let blur_pass = |values: &[f64], step: usize, len: usize, lines: usize, line_step: usize| {
+        let mut blurred = vec![0.0; values.len()];
+        for line in 0..lines {
+            for i in 0..len {
+                let mut sum = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let j = i as isize + k as isize - radius as isize;
+                    if j >= 0 && (j as usize) < len {
+                        sum += weight * values[line * line_step + j as usize * step];
+                    }
+                }
+                blurred[line * line_step + i * step] = sum;
+            }
+        }
+        blurred
+    };
+
+    let horizontal = blur_pass(values, 1, width, height, width);
+    blur_pass(&horizontal, width, height, width, 1)
+}
 
-        writer.write_image_data(&logo.data).unwrap(); // Save
+/// Draws an RGBA pixel over another one
+fn blend_over(dst: &mut [u8], src: [u8; 4]) {
+    let src_alpha = f64::from(src[3]) / 255.0;
+    let dst_alpha = f64::from(dst[3]) / 255.0 * (1.0 - src_alpha);
+    let alpha = src_alpha + dst_alpha;
+    if alpha == 0.0 {
+        return;
     }
 
-    Ok(result)
+    for channel in 0..3 {
+        let color = f64::from(src[channel]) * src_alpha + f64::from(dst[channel]) * dst_alpha;
+        dst[channel] = (color / alpha).round() as u8;
+    }
+    dst[3] = (alpha * 255.0).round() as u8;
 }
 
-fn get_logo_data(options: LogoOptions) -> Result<Logo, Box<dyn Error>> {
+fn render_logo(live_logo: &LogoResponse, options: &LogoOptions) -> Result<Logo, Box<dyn Error>> {
     let pixel_size = options.size.unwrap_or(1) as usize;
-    let live_logo = LOGO_CACHE.read();
+    let char_colors = options.char_colors()?;
 
     match options.character {
         None => {
@@ -82,13 +2147,22 @@ fn get_logo_data(options: LogoOptions) -> Result<Logo, Box<dyn Error>> {
 
             let mut image = vec![0; width * height * 4];
 
-            for (char_index, chr) in live_logo.logo.iter().enumerate() {
+            // The characters that don't fit are left out, see `check_fits`
+            for (char_index, chr) in live_logo.logo.iter().enumerate().take(MAX_PANELS.len()) {
                 let x = if char_index == 0 {
                     0
                 } else {
                     (char_index * 3 - 2) * 8
                 };
-                write_character(&chr, char_index, pixel_size, width, &mut image, x as i32, 0)?;
+                write_character_or_placeholder(
+                    &chr,
+                    char_index,
+                    char_colors[char_index],
+                    pixel_size,
+                    width,
+                    &mut image,
+                    (x as i32, 0),
+                )?;
             }
             Ok(Logo {
                 width,
@@ -106,12 +2180,13 @@ fn get_logo_data(options: LogoOptions) -> Result<Logo, Box<dyn Error>> {
             } else {
                 0
             };
+            let pad = options.pad.unwrap_or(0) as usize;
 
-            let height = (y + 32) as usize * pixel_size;
+            let height = ((y + 32) as usize + pad * 2) * pixel_size;
             let width = if character == 0 {
-                8 * pixel_size as usize
+                (8 + pad * 2) * pixel_size
             } else {
-                8 * 3 * pixel_size as usize
+                (8 * 3 + pad * 2) * pixel_size
             };
 
             let mut image = vec![0; width * height * 4];
@@ -121,7 +2196,15 @@ fn get_logo_data(options: LogoOptions) -> Result<Logo, Box<dyn Error>> {
                 .get(character)
                 .ok_or_else(|| format!("{} is not a valid character", character))?;
 
-            write_character(&chr, character, pixel_size, width, &mut image, 0, y)?;
+            write_character_or_placeholder(
+                &chr,
+                character,
+                char_colors.get(character).cloned().flatten(),
+                pixel_size,
+                width,
+                &mut image,
+                (pad as i32, y + pad as i32),
+            )?;
 
             Ok(Logo {
                 width,
@@ -132,14 +2215,60 @@ fn get_logo_data(options: LogoOptions) -> Result<Logo, Box<dyn Error>> {
     }
 }
 
+/// Draws a character, or gray panels in its place if its data is broken, so that one bad character
+/// doesn't take the rest of the logo down with it
+fn write_character_or_placeholder(
+    chr: &Vec<Vec<String>>,
+    char_index: usize,
+    color: Option<Color>,
+    pixel_size: usize,
+    width: usize,
+    image: &mut Vec<u8>,
+    letter_origin: (i32, i32),
+) -> Result<(), Box<dyn Error>> {
+    let err = match write_character(
+        chr,
+        char_index,
+        color,
+        pixel_size,
+        width,
+        image,
+        letter_origin,
+    ) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    eprintln!(
+        "Drawing a placeholder for character {}: {}",
+        char_index, err
+    );
+
+    let max_panels = MAX_PANELS
+        .get(char_index)
+        .ok_or_else(|| format!("{} is not a valid character", char_index))?;
+    // Pixels that aren't colors are drawn gray, even in a character with its own color
+    let placeholder = vec![vec![String::new(); 64]; *max_panels];
+    write_character(
+        &placeholder,
+        char_index,
+        None,
+        pixel_size,
+        width,
+        image,
+        letter_origin,
+    )
+}
+
+/// Draws a character, checking all of it before anything is drawn. With a `color`, every pixel of
+/// it is drawn in that color instead of its own.
 fn write_character(
     chr: &Vec<Vec<String>>,
     char_index: usize,
+    color: Option<Color>,
     pixel_size: usize,
     width: usize,
     image: &mut Vec<u8>,
-    letter_x: i32,
-    letter_y: i32,
+    (letter_x, letter_y): (i32, i32),
 ) -> Result<(), Box<dyn Error>> {
     let coords = vec![
         vec![[0, 0], [0, 16], [0, 24], [0, 32]],
@@ -168,40 +2297,41 @@ fn write_character(
         vec![[0, 8], [8, 8], [16, 8], [0, 16], [16, 16], [0, 24], [8, 24]],
     ];
 
-    for (panel_index, panel) in chr.iter().enumerate() {
-        for (pixel_index, pixel) in panel.iter().enumerate() {
+    let max_panels = MAX_PANELS
+        .get(char_index)
+        .ok_or_else(|| format!("{} is not a valid character", char_index))?;
+    if chr.len() > *max_panels {
+        return Err(format!("{} panels, but at most {} fit", chr.len(), max_panels).into());
+    }
+    let colors = chr
+        .iter()
+        .map(|panel| {
+            if panel.len() > 64 {
+                return Err(
+                    format!("a panel has {} pixels, but at most 64 fit", panel.len()).into(),
+                );
+            }
+            panel.iter().map(|pixel| parse_pixel(pixel)).collect()
+        })
+        .collect::<Result<Vec<Vec<_>>, Box<dyn Error>>>()?;
+
+    for (panel_index, panel) in colors.iter().enumerate() {
+        for (pixel_index, &(r, g, b)) in panel.iter().enumerate() {
+            let [r, g, b, a] = color.map_or([r, g, b, 255], |color| color.0);
             let panel_x = pixel_index % 8;
             let panel_y = pixel_index / 8;
-            let x = ((coords[char_index][panel_index][0] + panel_x) as i32 + letter_x) as usize;
-            let y = ((coords[char_index][panel_index][1] + panel_y) as i32 + letter_y) as usize;
+            let x = (coords[char_index][panel_index][0] + panel_x) as i64 + i64::from(letter_x);
+            let y = (coords[char_index][panel_index][1] + panel_y) as i64 + i64::from(letter_y);
 
             for extra_x in 0..pixel_size {
                 for extra_y in 0..pixel_size {
-                    let x = extra_x + (x * pixel_size);
-                    let y = extra_y + (y * pixel_size);
-
-                    let (r, g, b) = if pixel.len() == 7 {
-                        (
-                            u8::from_str_radix(&pixel[1..3], 16)?,
-                            u8::from_str_radix(&pixel[3..5], 16)?,
-                            u8::from_str_radix(&pixel[5..7], 16)?,
-                        )
-                    } else if pixel.len() == 6 {
-                        (
-                            u8::from_str_radix(&pixel[0..2], 16)?,
-                            u8::from_str_radix(&pixel[2..4], 16)?,
-                            u8::from_str_radix(&pixel[4..6], 16)?,
-                        )
-                    } else {
-                        (155, 155, 155)
-                    };
-
-                    let image_idx = (x + y * width) * 4;
+                    let image_idx =
+                        image_index(image, width, pixel_size, (x, y), (extra_x, extra_y))?;
 
                     image[image_idx] = r;
                     image[image_idx + 1] = g;
                     image[image_idx + 2] = b;
-                    image[image_idx + 3] = 255;
+                    image[image_idx + 3] = a;
                 }
             }
         }
@@ -209,3 +2339,756 @@ fn write_character(
 
     Ok(())
 }
+
+/// Where the pixel `extra` into logical pixel `x`, `y` of the logo starts in an RGBA image `width`
+/// pixels wide. A pixel outside of the image is an error, rather than a panic or a pixel drawn in
+/// the wrong row.
+fn image_index(
+    image: &[u8],
+    width: usize,
+    pixel_size: usize,
+    (x, y): (i64, i64),
+    (extra_x, extra_y): (usize, usize),
+) -> Result<usize, String> {
+    let outside = || format!("pixel {}, {} is outside of the image", x, y);
+    let scale = |logical: i64, extra: usize| {
+        usize::try_from(logical)
+            .ok()
+            .and_then(|logical| logical.checked_mul(pixel_size))
+            .and_then(|scaled| scaled.checked_add(extra))
+    };
+    let x = scale(x, extra_x)
+        .filter(|&x| x < width)
+        .ok_or_else(outside)?;
+    let y = scale(y, extra_y).ok_or_else(outside)?;
+
+    y.checked_mul(width)
+        .and_then(|row| row.checked_add(x))
+        .and_then(|pixel| pixel.checked_mul(4))
+        .filter(|&index| index + 4 <= image.len())
+        .ok_or_else(outside)
+}
+
+/// Reads a `#rrggbb` or `rrggbb` pixel, where anything of another length is gray
+fn parse_pixel(pixel: &str) -> Result<(u8, u8, u8), Box<dyn Error>> {
+    let hex = match pixel.len() {
+        // The first character is normally `#`, but might not even be ascii
+        7 => pixel.get(1..).unwrap_or(""),
+        6 => pixel,
+        _ => return Ok((155, 155, 155)),
+    };
+    let channel = |index: usize| {
+        hex.get(index..index + 2)
+            .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+            .ok_or_else(|| format!("{:?} is not a color", pixel))
+    };
+    Ok((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    lazy_static! {
+        // The tests share the logo cache, so they must not run at the same time
+        static ref CACHE_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    const RED_LOGO: &str = r##"{"logo": [[["#ff0000"]]]}"##;
+    const BLUE_LOGO: &str = r##"{"logo": [[["#0000ff"]]]}"##;
+
+    /// What the mock upstream does on a poll
+    enum Tick {
+        Logo(&'static str),
+        NotModified,
+        Fail(&'static str),
+    }
+
+    /// Plays back a scripted sequence of upstream responses
+    struct MockFetcher {
+        ticks: RefCell<VecDeque<Tick>>,
+    }
+
+    impl MockFetcher {
+        fn new(ticks: Vec<Tick>) -> Self {
+            MockFetcher {
+                ticks: RefCell::new(ticks.into()),
+            }
+        }
+    }
+
+    impl Fetcher for MockFetcher {
+        fn fetch(&self) -> Result<Option<LogoResponse>, Box<dyn Error>> {
+            match self.ticks.borrow_mut().pop_front().expect("no more ticks") {
+                Tick::Logo(json) => Ok(Some(serde_json::from_str(json)?)),
+                Tick::NotModified => Ok(None),
+                Tick::Fail(err) => Err(err.into()),
+            }
+        }
+    }
+
+    /// Polls `fetcher` once per tick, with an empty cache to start with, and returns whether each
+    /// poll would have published an update
+    fn poll_all(fetcher: &MockFetcher) -> Vec<Result<bool, String>> {
+        let config = Config::default();
+        *LOGO_CACHE.write() = LogoResponse { logo: vec![] };
+        *PRERENDERED.write() = None;
+
+        let mut results = vec![];
+        while !fetcher.ticks.borrow().is_empty() {
+            let result = fetch_logo(&config, fetcher);
+            results.push(
+                result
+                    .map(|png| png.is_some())
+                    .map_err(|err| err.to_string()),
+            );
+        }
+        results
+    }
+
+    /// Records the changes it is given, instead of sending and storing them
+    #[derive(Default)]
+    struct MockPublisher {
+        updates: RefCell<Vec<Vec<u8>>>,
+        saved: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl Publisher for MockPublisher {
+        fn send_update(&self, _config: &Config, logo_png: &[u8]) {
+            self.updates.borrow_mut().push(logo_png.to_vec());
+        }
+
+        fn save_logo(
+            &self,
+            _config: &Config,
+            logo_png: &[u8],
+            _thumbnail: Option<&[u8]>,
+        ) -> Result<(), Box<dyn Error>> {
+            self.saved.borrow_mut().push(logo_png.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Runs `update_logo` once per tick, like the poller, with an empty cache and no change held
+    /// back to start with
+    fn update_all(fetcher: &MockFetcher, publisher: &MockPublisher) -> Vec<Result<(), String>> {
+        let config = Config::default();
+        *LOGO_CACHE.write() = LogoResponse { logo: vec![] };
+        *PRERENDERED.write() = None;
+        *HISTORY_WRITES.lock() = HistoryWrites::default();
+
+        let mut results = vec![];
+        while !fetcher.ticks.borrow().is_empty() {
+            let result = update_logo(&config, fetcher, publisher);
+            results.push(result.map_err(|err| err.to_string()));
+        }
+        results
+    }
+
+    /// A logo where every pixel is set, with a distinct color for each character and panel
+    fn full_logo() -> LogoResponse {
+        let panels = [3, 7, 8, 5, 7, 7, 7];
+        LogoResponse {
+            logo: panels
+                .iter()
+                .enumerate()
+                .map(|(char_index, &panel_count)| {
+                    (0..panel_count)
+                        .map(|panel_index| {
+                            (0..64)
+                                .map(|pixel| {
+                                    format!(
+                                        "#{:02x}{:02x}{:02x}",
+                                        char_index * 30,
+                                        panel_index * 30,
+                                        pixel * 4
+                                    )
+                                })
+                                .collect()
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    fn render(character: usize, size: u32, crop: bool) -> Logo {
+        render_logo(
+            &full_logo(),
+            &LogoOptions {
+                character: Some(character),
+                size: Some(size),
+                crop,
+                ..LogoOptions::default()
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_panels_outside_the_image() {
+        assert_eq!(full_logo().validate(), Ok(()));
+
+        let mut logo = full_logo();
+        logo.logo[0].push(vec!["#ffffff".to_owned()]);
+        assert!(logo.validate().is_err());
+
+        let mut logo = full_logo();
+        logo.logo[2][0].push("#ffffff".to_owned());
+        assert!(logo.validate().is_err());
+
+        let mut logo = full_logo();
+        logo.logo.push(vec![]);
+        assert!(logo.validate().is_err());
+    }
+
+    #[test]
+    fn draws_placeholders_for_broken_characters() {
+        let mut broken = full_logo();
+        broken.logo[2][0][0] = "#zzzzzz".to_owned();
+        broken.logo[4].push(vec![]);
+        broken.logo.push(vec![]);
+
+        let options = LogoOptions::default();
+        let full = render_logo(&full_logo(), &options).unwrap();
+        let logo = render_logo(&broken, &options).unwrap();
+
+        let pixel = |logo: &Logo, x: usize, y: usize| {
+            let index = (x + y * logo.width) * 4;
+            logo.data[index..index + 4].to_vec()
+        };
+        for y in 0..logo.height {
+            for x in 0..logo.width {
+                // Characters 2 and 4 start at 32 and 80, and are 24 pixels wide
+                let expected = if (32..56).contains(&x) || (80..104).contains(&x) {
+                    let drawn = pixel(&full, x, y)[3] == 255;
+                    if drawn {
+                        vec![155, 155, 155, 255]
+                    } else {
+                        vec![0; 4]
+                    }
+                } else {
+                    pixel(&full, x, y)
+                };
+                assert_eq!(pixel(&logo, x, y), expected, "pixel {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn sizes_single_characters() {
+        let logo = render(3, 4, false);
+        assert_eq!((logo.width, logo.height), (24 * 4, 32 * 4));
+
+        let logo = render(3, 4, true);
+        assert_eq!((logo.width, logo.height), (24 * 4, 24 * 4));
+
+        let logo = render(0, 3, true);
+        assert_eq!((logo.width, logo.height), (8 * 3, 32 * 3));
+    }
+
+    #[test]
+    fn crops_whole_logical_pixels() {
+        for size in 1..5 {
+            let full = render(3, size, false);
+            let cropped = render(3, size, true);
+            // Cropping removes the 8 empty logical rows above the character
+            let offset = 8 * size as usize * full.width * 4;
+            assert_eq!(cropped.data[..], full.data[offset..]);
+        }
+    }
+
+    #[test]
+    fn pads_single_characters() {
+        for &(character, width) in &[(0, 8), (3, 24)] {
+            let unpadded = render(character, 2, true);
+            let padded = render_logo(
+                &full_logo(),
+                &LogoOptions {
+                    character: Some(character),
+                    size: Some(2),
+                    crop: true,
+                    pad: Some(3),
+                    ..LogoOptions::default()
+                },
+            )
+            .unwrap();
+
+            // 3 logical pixels of 2 pixels each on every side
+            assert_eq!(padded.width, (width + 6) * 2);
+            assert_eq!(padded.height, unpadded.height + 12);
+            for y in 0..padded.height {
+                for x in 0..padded.width {
+                    let idx = (y * padded.width + x) * 4;
+                    let inside = (6..unpadded.width + 6).contains(&x)
+                        && (6..unpadded.height + 6).contains(&y);
+                    let expected = if inside {
+                        let idx = ((y - 6) * unpadded.width + x - 6) * 4;
+                        &unpadded.data[idx..idx + 4]
+                    } else {
+                        &[0; 4][..]
+                    };
+                    assert_eq!(&padded.data[idx..idx + 4], expected, "pixel ({}, {})", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scales_single_characters_by_whole_pixels() {
+        for character in 0..7 {
+            for &crop in &[false, true] {
+                let small = render(character, 1, crop);
+                for size in 2..5 {
+                    let large = render(character, size, crop);
+                    let size = size as usize;
+
+                    assert_eq!(large.width, small.width * size);
+                    assert_eq!(large.height, small.height * size);
+                    // Every output pixel is a copy of the logical pixel it covers
+                    for y in 0..large.height {
+                        for x in 0..large.width {
+                            let large_idx = (y * large.width + x) * 4;
+                            let small_idx = ((y / size) * small.width + x / size) * 4;
+                            assert_eq!(
+                                large.data[large_idx..large_idx + 4],
+                                small.data[small_idx..small_idx + 4],
+                                "character {} at size {} with crop {}, pixel ({}, {})",
+                                character,
+                                size,
+                                crop,
+                                x,
+                                y
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn draws_shadows_behind_the_logo() {
+        assert_eq!(
+            "4:000000aa".parse(),
+            Ok(Shadow {
+                blur: 4,
+                color: Color([0, 0, 0, 0xaa])
+            })
+        );
+        for invalid in &["4", "0:000000", "33:000000", "x:000000", "4:black"] {
+            assert!(invalid.parse::<Shadow>().is_err(), "{}", invalid);
+        }
+
+        let logo = Logo {
+            width: 8,
+            height: 8,
+            data: [255, 0, 0, 255].repeat(64),
+        };
+        let shadowed = logo.with_shadow("4:000000ff".parse().unwrap());
+        assert_eq!((shadowed.width, shadowed.height), (20, 20));
+
+        let pixel = |x: usize, y: usize| {
+            let index = (x + y * shadowed.width) * 4;
+            shadowed.data[index..index + 4].to_vec()
+        };
+        for y in 4..12 {
+            for x in 4..12 {
+                assert_eq!(pixel(x, y), vec![255, 0, 0, 255]);
+            }
+        }
+        // The shadow is offset down and to the right, and fades out at its edges
+        assert_eq!(pixel(0, 0)[3], 0);
+        assert_eq!(pixel(3, 14)[3], 0);
+        assert!(pixel(14, 14)[3] > 128);
+        assert!(pixel(18, 18)[3] < pixel(14, 14)[3]);
+        assert_eq!(pixel(14, 14)[..3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn flips_the_logo() {
+        // A 3x2 logo where the pixel at (0, 0) is red and every other one is transparent
+        let flipped = |flip: Flip| {
+            let mut data = vec![0; 3 * 2 * 4];
+            data[..4].copy_from_slice(&[255, 0, 0, 255]);
+            let mut logo = Logo {
+                width: 3,
+                height: 2,
+                data,
+            };
+            logo.flip(flip);
+            let index = logo.data.chunks(4).position(|pixel| pixel[3] != 0).unwrap();
+            assert_eq!(logo.data.chunks(4).filter(|pixel| pixel[3] != 0).count(), 1);
+            (index % 3, index / 3)
+        };
+
+        assert_eq!(flipped(Flip::H), (2, 0));
+        assert_eq!(flipped(Flip::V), (0, 1));
+        assert_eq!(flipped(Flip::Hv), (2, 1));
+
+        assert_eq!("hv".parse(), Ok(Flip::Hv));
+        assert!("x".parse::<Flip>().is_err());
+    }
+
+    #[test]
+    fn tints_every_visible_pixel() {
+        let original = render_logo(&full_logo(), &LogoOptions::default()).unwrap();
+        let mut logo = render_logo(&full_logo(), &LogoOptions::default()).unwrap();
+        logo.tint("ff6600".parse().unwrap());
+
+        for (pixel, original) in logo.data.chunks(4).zip(original.data.chunks(4)) {
+            assert_eq!(pixel[3], original[3]);
+            if pixel[3] != 0 {
+                assert_eq!(pixel[..3], [0xff, 0x66, 0x00]);
+            }
+        }
+        assert!(original.data.chunks(4).any(|pixel| pixel[3] == 0));
+    }
+
+    #[test]
+    fn finds_the_dominant_color() {
+        // Two red pixels, one blue and one transparent
+        let logo = Logo {
+            width: 2,
+            height: 2,
+            data: vec![255, 0, 0, 255, 0, 0, 255, 255, 255, 0, 0, 128, 0, 0, 0, 0],
+        };
+        let dominant = logo.dominant();
+        assert_eq!(dominant.palette.len(), 2);
+        assert_eq!(dominant.dominant.as_ref(), dominant.palette.first());
+        assert_eq!(
+            dominant.dominant.unwrap(),
+            PaletteColor {
+                color: "#ff0000".to_owned(),
+                red: 255,
+                green: 0,
+                blue: 0,
+                share: 2.0 / 3.0,
+            }
+        );
+        assert_eq!(
+            Logo {
+                width: 1,
+                height: 1,
+                data: vec![0; 4],
+            }
+            .dominant()
+            .dominant,
+            None
+        );
+    }
+
+    #[test]
+    fn fails_to_draw_outside_of_the_image() {
+        let chr = &full_logo().logo[1];
+        let mut image = vec![0; 152 * 32 * 4];
+        let mut draw = |origin| write_character(chr, 1, None, 1, 152, &mut image, origin);
+        assert!(draw((0, 0)).is_ok());
+        // Left of the image, off the right edge, and below the bottom
+        assert!(draw((-8, 0)).is_err());
+        assert!(draw((140, 0)).is_err());
+        assert!(draw((0, 8)).is_err());
+
+        // An image that is too small for the character
+        let mut small = vec![0; 8 * 8 * 4];
+        assert!(write_character(chr, 1, None, 1, 8, &mut small, (0, 0)).is_err());
+    }
+
+    #[test]
+    fn colors_characters_in_rainbow_order() {
+        let palette: Palette = "ff0000,00ff00".parse().unwrap();
+        let options = LogoOptions {
+            rainbow: true,
+            char_color: Some("3:ffffff".to_owned()),
+            ..LogoOptions::default()
+        }
+        .with_rainbow(&palette);
+        let colors = options.char_colors().unwrap();
+        assert_eq!(colors[0], Some(Color([255, 0, 0, 255])));
+        assert_eq!(colors[1], Some(Color([0, 255, 0, 255])));
+        assert_eq!(colors[2], Some(Color([255, 0, 0, 255])));
+        // Characters given their own color keep it
+        assert_eq!(colors[3], Some(Color([255, 255, 255, 255])));
+        assert_eq!(colors[6], Some(Color([255, 0, 0, 255])));
+    }
+
+    #[test]
+    fn holds_back_history_writes() {
+        let mut writes = HistoryWrites::default();
+        let interval = Duration::from_secs(60);
+        assert_eq!(writes.take_due(Some(vec![1]), interval), Some(vec![1]));
+        assert_eq!(writes.take_due(Some(vec![2]), interval), None);
+        assert_eq!(writes.take_due(Some(vec![3]), interval), None);
+        assert_eq!(writes.take_due(None, interval), None);
+        // The latest change is stored once the interval has passed
+        assert_eq!(writes.take_due(None, Duration::from_secs(0)), Some(vec![3]));
+        assert_eq!(writes.take_due(None, Duration::from_secs(0)), None);
+    }
+
+    #[test]
+    fn encodes_palette_png() {
+        // 5 colors, which take 4 bits each, in rows of 3 that end in the middle of a byte
+        let colors = [
+            [255, 0, 0, 255],
+            [0, 0, 255, 255],
+            [0, 0, 0, 0],
+            [0, 255, 0, 128],
+        ];
+        let data: Vec<u8> = (0..12)
+            .flat_map(|pixel| match pixel {
+                11 => [255, 255, 255, 255],
+                _ => colors[pixel % colors.len()],
+            })
+            .collect();
+        let logo = Logo {
+            width: 3,
+            height: 4,
+            data,
+        };
+        let palette = encode_palette_png(&logo, false).unwrap().unwrap();
+        assert_eq!(palette[24], 4);
+
+        // Decoding expands the palette to RGBA, as it has transparent pixels
+        let decoded = decode_png(&palette).unwrap();
+        assert_eq!((decoded.width, decoded.height), (logo.width, logo.height));
+        assert_eq!(decoded.data, logo.data);
+
+        let many_colors = Logo {
+            width: 257,
+            height: 1,
+            data: (0..257u32)
+                .flat_map(|x| [x as u8, (x >> 8) as u8, 0, 255])
+                .collect(),
+        };
+        assert!(encode_palette_png(&many_colors, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn encodes_png_deterministically() {
+        let logo = render_logo(&full_logo(), &LogoOptions::default()).unwrap();
+        let first = encode_png(&logo, false).unwrap();
+        let second = encode_png(&logo, false).unwrap();
+        assert_eq!(first, second);
+
+        // Only the chunks needed for the pixels are written, so nothing varies between runs
+        assert_eq!(png_chunks(&first), vec!["IHDR", "IDAT", "IEND"]);
+    }
+
+    #[test]
+    fn scales_down_as_light() {
+        // Black next to white
+        let logo = Logo {
+            width: 2,
+            height: 1,
+            data: vec![0, 0, 0, 255, 255, 255, 255, 255],
+        };
+
+        // Half as much light as white is much brighter than halfway between the sRGB values
+        assert_eq!(logo.resize(1, 1, true).data, vec![188, 188, 188, 255]);
+        assert_eq!(logo.resize(1, 1, false).data, vec![128, 128, 128, 255]);
+        // Every channel value survives the round trip
+        for channel in 0..=255 {
+            assert_eq!(from_linear(channel_value(channel, true)), channel);
+        }
+    }
+
+    #[test]
+    fn encodes_16_bit_png() {
+        let logo = Logo {
+            width: 2,
+            height: 1,
+            data: vec![0, 128, 255, 255, 1, 2, 3, 0],
+        };
+        let png = encode_png_with_depth(&logo, false, png::BitDepth::Sixteen).unwrap();
+
+        let mut decoder = png::Decoder::new(&png[..]);
+        // Keep the decoder from stripping the channels to 8 bits
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        let channels: Vec<u16> = data
+            .chunks(2)
+            .map(|channel| u16::from_be_bytes([channel[0], channel[1]]))
+            .collect();
+        assert_eq!(channels, vec![0, 32896, 65535, 65535, 257, 514, 771, 0]);
+    }
+
+    #[test]
+    fn finds_the_changed_characters() {
+        let options = LogoOptions::default();
+        let png =
+            |logo: &LogoResponse| encode_png(&render_logo(logo, &options).unwrap(), true).unwrap();
+        let old = png(&full_logo());
+
+        assert_eq!(changed_characters(&old, &old).unwrap(), 0);
+
+        let mut logo = full_logo();
+        logo.logo[0][2][63] = "#ffffff".to_owned();
+        logo.logo[3][0][0] = "#ffffff".to_owned();
+        assert_eq!(changed_characters(&old, &png(&logo)).unwrap(), 0b000_1001);
+
+        // A logo at another size can't be compared, so everything changed
+        let large = encode_png(
+            &render_logo(
+                &full_logo(),
+                &LogoOptions {
+                    size: Some(2),
+                    ..LogoOptions::default()
+                },
+            )
+            .unwrap(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(changed_characters(&old, &large).unwrap(), 0b111_1111);
+    }
+
+    #[test]
+    fn marks_pngs_as_srgb() {
+        let logo = render_logo(&full_logo(), &LogoOptions::default()).unwrap();
+        let png = encode_png(&logo, true).unwrap();
+
+        assert_eq!(
+            png_chunks(&png),
+            vec!["IHDR", "sRGB", "gAMA", "IDAT", "IEND"]
+        );
+        // The pixels are the same either way
+        let decoded = |png: &[u8]| {
+            let (info, mut reader) = png::Decoder::new(png).read_info().unwrap();
+            let mut data = vec![0; info.buffer_size()];
+            reader.next_frame(&mut data).unwrap();
+            data
+        };
+        assert_eq!(decoded(&png), decoded(&encode_png(&logo, false).unwrap()));
+    }
+
+    /// The types of the chunks of a PNG, once for each run of the same type
+    fn png_chunks(png: &[u8]) -> Vec<String> {
+        let mut chunks = vec![];
+        let mut offset = 8;
+        while offset < png.len() {
+            let mut length = [0; 4];
+            length.copy_from_slice(&png[offset..offset + 4]);
+            chunks.push(String::from_utf8_lossy(&png[offset + 4..offset + 8]).into_owned());
+            offset += 12 + u32::from_be_bytes(length) as usize;
+        }
+        chunks.dedup();
+        chunks
+    }
+
+    #[test]
+    fn publishes_changes() {
+        let _lock = CACHE_LOCK.lock();
+        let fetcher = MockFetcher::new(vec![Tick::Logo(RED_LOGO), Tick::Logo(BLUE_LOGO)]);
+        let publisher = MockPublisher::default();
+
+        assert_eq!(update_all(&fetcher, &publisher), vec![Ok(()), Ok(())]);
+        assert_eq!(LOGO_CACHE.read().logo[0][0][0], "#0000ff");
+
+        // Each change is sent and stored once, as the PNG of the logo it changed to
+        let updates = publisher.updates.borrow();
+        assert_eq!(updates.len(), 2);
+        assert_ne!(updates[0], updates[1]);
+        assert_eq!(*publisher.saved.borrow(), *updates);
+        let config = Config::default();
+        assert_eq!(
+            updates[1],
+            get_logo_image(&config, &LogoOptions::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn ignores_unchanged_logos() {
+        let _lock = CACHE_LOCK.lock();
+        let fetcher = MockFetcher::new(vec![
+            Tick::Logo(RED_LOGO),
+            Tick::Logo(RED_LOGO),
+            Tick::NotModified,
+        ]);
+        let publisher = MockPublisher::default();
+
+        assert_eq!(
+            update_all(&fetcher, &publisher),
+            vec![Ok(()), Ok(()), Ok(())]
+        );
+        // Only the first tick changed the logo
+        assert_eq!(publisher.updates.borrow().len(), 1);
+        assert_eq!(publisher.saved.borrow().len(), 1);
+    }
+
+    #[test]
+    fn publishes_nothing_when_the_upstream_fails() {
+        let _lock = CACHE_LOCK.lock();
+        let fetcher = MockFetcher::new(vec![
+            Tick::Logo(RED_LOGO),
+            Tick::Fail("timed out"),
+            Tick::Logo(RED_LOGO),
+        ]);
+        let publisher = MockPublisher::default();
+
+        assert_eq!(
+            update_all(&fetcher, &publisher),
+            vec![Ok(()), Err("timed out".to_owned()), Ok(())]
+        );
+        // The logo after the failure is the one from before it, so it isn't a change
+        assert_eq!(publisher.updates.borrow().len(), 1);
+        assert_eq!(publisher.saved.borrow().len(), 1);
+    }
+
+    #[test]
+    fn prerenders_the_common_sizes() {
+        let _lock = CACHE_LOCK.lock();
+        let fetcher = MockFetcher::new(vec![Tick::Logo(RED_LOGO), Tick::Logo(BLUE_LOGO)]);
+        poll_all(&fetcher);
+
+        let config = Config::default();
+        let options = LogoOptions {
+            size: Some(2),
+            ..LogoOptions::default()
+        };
+        let prerendered = get_prerendered(&config, &options).unwrap();
+        assert_eq!(
+            prerendered,
+            render_image(&config, &LOGO_CACHE.read(), &options).unwrap()
+        );
+        // A reloaded config may draw the logo differently
+        let reloaded = Config {
+            generation: 1,
+            ..config.clone()
+        };
+        assert!(get_prerendered(&reloaded, &options).is_none());
+        // Other options are rendered for each request
+        let smooth = LogoOptions {
+            smooth: true,
+            ..options
+        };
+        assert!(get_prerendered(&config, &smooth).is_none());
+    }
+
+    #[test]
+    fn keeps_the_logo_on_errors() {
+        let _lock = CACHE_LOCK.lock();
+        let fetcher = MockFetcher::new(vec![
+            Tick::Logo(RED_LOGO),
+            Tick::Fail("operation timed out"),
+            Tick::Logo("{\"logo\": 5}"),
+            Tick::Logo(RED_LOGO),
+        ]);
+
+        let results = poll_all(&fetcher);
+
+        assert_eq!(results[0], Ok(true));
+        assert_eq!(results[1], Err("operation timed out".to_owned()));
+        assert!(results[2].is_err());
+        // Recovering with the same logo is not a change
+        assert_eq!(results[3], Ok(false));
+        assert_eq!(LOGO_CACHE.read().logo[0][0][0], "#ff0000");
+    }
+}
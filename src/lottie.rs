@@ -0,0 +1,122 @@
+use serde_json::{json, Value};
+
+/// Frames per second of the animations
+const FRAME_RATE: u32 = 30;
+
+/// A pixel that has a different color in the new logo
+pub struct PixelChange {
+    pub x: usize,
+    pub y: usize,
+    pub from: [u8; 4],
+    pub to: [u8; 4],
+}
+
+/// A Lottie animation of `width` by `height` pixels that fades each changed pixel from its old
+/// color to its new one over `duration_ms`. Only the changed pixels are drawn, so it is meant to
+/// be played over the old logo.
+pub fn transition(width: usize, height: usize, changes: &[PixelChange], duration_ms: u32) -> Value {
+    let frames = (duration_ms * FRAME_RATE / 1000).max(1);
+    let pixels: Vec<Value> = changes.iter().map(|change| pixel(change, frames)).collect();
+
+    json!({
+        "v": "5.7.4",
+        "nm": "Logo transition",
+        "fr": FRAME_RATE,
+        "ip": 0,
+        "op": frames,
+        "w": width,
+        "h": height,
+        "ddd": 0,
+        "assets": [],
+        "layers": [{
+            "ty": 4,
+            "nm": "Changed pixels",
+            "ind": 1,
+            "ip": 0,
+            "op": frames,
+            "st": 0,
+            "sr": 1,
+            "ks": {
+                "o": fixed(json!(100)),
+                "r": fixed(json!(0)),
+                "p": fixed(json!([0, 0, 0])),
+                "a": fixed(json!([0, 0, 0])),
+                "s": fixed(json!([100, 100, 100])),
+            },
+            "shapes": pixels,
+        }],
+    })
+}
+
+/// A group drawing one pixel as a square, with its color and opacity going from old to new
+fn pixel(change: &PixelChange, frames: u32) -> Value {
+    // A transparent pixel has no color of its own, so it fades in or out in the other color
+    let from_rgb = if change.from[3] == 0 {
+        change.to
+    } else {
+        change.from
+    };
+    let to_rgb = if change.to[3] == 0 {
+        change.from
+    } else {
+        change.to
+    };
+
+    json!({
+        "ty": "gr",
+        "nm": format!("{},{}", change.x, change.y),
+        "it": [
+            {
+                "ty": "rc",
+                "p": fixed(json!([change.x as f64 + 0.5, change.y as f64 + 0.5])),
+                "s": fixed(json!([1, 1])),
+                "r": fixed(json!(0)),
+            },
+            {
+                "ty": "fl",
+                "c": animated(color(from_rgb), color(to_rgb), frames),
+                "o": animated(opacity(change.from), opacity(change.to), frames),
+                "r": 1,
+            },
+            {
+                "ty": "tr",
+                "p": fixed(json!([0, 0])),
+                "a": fixed(json!([0, 0])),
+                "s": fixed(json!([100, 100])),
+                "r": fixed(json!(0)),
+                "o": fixed(json!(100)),
+            },
+        ],
+    })
+}
+
+fn fixed(value: Value) -> Value {
+    json!({ "a": 0, "k": value })
+}
+
+/// A property going linearly from `from` at the first frame to `to` at the last
+fn animated(from: Value, to: Value, frames: u32) -> Value {
+    json!({
+        "a": 1,
+        "k": [
+            {
+                "t": 0,
+                "s": from,
+                "o": { "x": [0], "y": [0] },
+                "i": { "x": [1], "y": [1] },
+            },
+            { "t": frames, "s": to },
+        ],
+    })
+}
+
+/// A color as Lottie has it, with channels from 0 to 1
+fn color(pixel: [u8; 4]) -> Value {
+    let channel = |value: u8| f64::from(value) / 255.0;
+    json!([channel(pixel[0]), channel(pixel[1]), channel(pixel[2]), 1])
+}
+
+/// The opacity of a pixel from 0 to 100, wrapped like the other animated values
+fn opacity(pixel: [u8; 4]) -> Value {
+    json!([f64::from(pixel[3]) * 100.0 / 255.0])
+}
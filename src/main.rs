@@ -1,114 +1,980 @@
 #![recursion_limit = "256"]
 
 use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 use dotenv::dotenv;
 use futures::future::poll_fn;
+use serde::Serialize;
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use structopt::StructOpt;
 use tokio_threadpool::blocking;
 use warp::{
     self,
     http::{self, Response},
-    path, reply, Filter,
+    path, reply, Buf, Filter, Rejection, Reply,
 };
 
+use crate::budget::Budget;
+use crate::config::{Config, ConfigHandle};
+use crate::upstream::Fetcher;
+
+mod body;
+mod budget;
+mod capabilities;
+mod client_ip;
+mod coalesce;
+mod color;
+mod config;
+mod connection;
 mod db;
+mod font;
+mod ico;
 mod live;
 mod logo;
+mod lottie;
+mod metrics;
+mod mjpeg;
+mod upstream;
+mod zip;
+
+/// How often the watchdog checks that the poller is running
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Serves the iterate logo as png")]
+struct Args {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Fetch the live logo once and write it to a file, without starting the server
+    Render {
+        /// File to write the image to, instead of stdout
+        #[structopt(long, parse(from_os_str))]
+        out: Option<PathBuf>,
+        #[structopt(flatten)]
+        options: logo::LogoOptions,
+    },
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
 
-    db::init_db()?;
+    let args = Args::from_args();
+    let config = config::load()?;
 
-    let logo_options = warp::query::<logo::LogoOptions>();
-    let get_history_options = warp::query::<db::GetHistoryOptions>();
+    match args.command {
+        None => serve(config),
+        Some(Command::Render { out, options }) => render(&config, out, &options),
+    }
+}
+
+fn render(
+    config: &Config,
+    out: Option<PathBuf>,
+    options: &logo::LogoOptions,
+) -> Result<(), Box<dyn Error>> {
+    let options = &options.clone().with_default_format(config.default_format);
+    options.validate(config)?;
+
+    let live_logo = upstream::from_config(config)?
+        .fetch()?
+        .ok_or("The upstream sent no logo")?;
+    let image = logo::render_image(config, &live_logo, options)?;
+
+    match out {
+        Some(path) => fs::write(path, image)?,
+        None => io::stdout().write_all(&image)?,
+    }
+    Ok(())
+}
+
+fn serve(config: Config) -> Result<(), Box<dyn Error>> {
+    config::require_database(&config)?;
+    let config = Arc::new(ConfigHandle::new(config));
+
+    db::init_db(&config.get())?;
+
+    {
+        // Start the poller again if it panics
+        let config = config.clone();
+        let fetcher: Arc<dyn Fetcher + Send + Sync> = upstream::from_config(&config.get())?.into();
+        let mut poller = spawn_poller(config.clone(), fetcher.clone());
+        thread::spawn(move || loop {
+            thread::sleep(WATCHDOG_INTERVAL);
+            if poller.is_finished() {
+                println!("The poller stopped, starting it again");
+                poller = spawn_poller(config.clone(), fetcher.clone());
+            }
+        });
+    }
 
     thread::spawn(|| loop {
-        if let Err(err) = logo::update_logo() {
+        thread::sleep(live::SWEEP_INTERVAL);
+        live::sweep_listeners();
+    });
+
+    {
+        // Reload the config on SIGHUP
+        let config = config.clone();
+        let mut signals = Signals::new([SIGHUP])?;
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                match config.reload() {
+                    Ok(()) => println!("Reloaded config"),
+                    Err(err) => println!("Error reloading config, keeping the old one: {}", err),
+                }
+            }
+        });
+    }
+
+    let bind_addr = config.get().bind_addr;
+    let idle_timeout = Some(Duration::from_secs(config.get().idle_timeout))
+        .filter(|idle_timeout| *idle_timeout > Duration::from_secs(0));
+    let incoming = connection::Incoming::bind(&bind_addr, idle_timeout)?;
+    warp::serve(routes(config)).run_incoming(incoming);
+
+    Ok(())
+}
+
+/// Polls the upstream for the live logo on a thread of its own
+fn spawn_poller(
+    config: Arc<ConfigHandle>,
+    fetcher: Arc<dyn Fetcher + Send + Sync>,
+) -> thread::JoinHandle<()> {
+    logo::record_poll();
+    thread::spawn(move || loop {
+        let config = config.get();
+        if let Err(err) = logo::update_logo(&config, &*fetcher, &logo::LivePublisher) {
             println!("Error updating logo: {}", err);
         }
-        thread::sleep(Duration::from_secs(1));
-    });
+        logo::record_poll();
+        thread::sleep(Duration::from_secs(config.poll_interval));
+    })
+}
+
+fn routes(
+    config_handle: Arc<ConfigHandle>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let logo_options = warp::query::<logo::LogoOptions>();
+    let get_history_options = warp::query::<db::GetHistoryOptions>();
+    // Every POST route must limit the size of its body
+    // The settings used to build the routes are only read once, see `ConfigHandle::reload`
+    let config = config_handle.get();
+    let post_body_limit = warp::body::content_length_limit(config.max_body_size);
+    let with_config = warp::any().map(move || config_handle.get());
 
     // Note: Warp also applies cors-filter on websockets
+    // The origin is checked against the current config by `allow_cors_origin`
     let cors = warp::cors()
-        .allow_origin("http://localhost:8000")
-        .allow_methods(vec!["GET"]);
+        .allow_any_origin()
+        .allow_methods(vec!["GET", "POST"])
+        .allow_header("content-type");
 
-    // GET /logo.png
-    let logo = path!("logo.png").and(logo_options).and_then(|options| {
-        poll_fn(move || blocking(|| logo_route(options)).map_err(|err| warp::reject::custom(err)))
+    // GET /logo.png and /logo, which are the same despite the extension
+    let logo = path!("logo.png")
+        .or(path!("logo"))
+        .unify()
+        .and(logo_options)
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("sec-ch-dpr"))
+        .and(warp::header::optional::<String>("dpr"))
+        .and(with_config.clone())
+        .and_then(
+            |options: logo::LogoOptions,
+             accept: Option<String>,
+             sec_ch_dpr: Option<String>,
+             dpr: Option<String>,
+             config: Arc<Config>| {
+                let mut options = options.with_default_format(config.default_format);
+                // Without a size, the client hint decides it
+                let sized_by_hint = options.size().is_none();
+                if let Some(dpr) = device_pixel_ratio(sec_ch_dpr.as_deref(), dpr.as_deref()) {
+                    options = options.with_device_pixel_ratio(dpr, &config.allowed_sizes);
+                }
+                poll_fn(move || {
+                    blocking(|| logo_route(&config, &options, accept.as_deref(), sized_by_hint))
+                        .map_err(warp::reject::custom)
+                })
+            },
+        );
+    // GET /favicon.ico
+    let favicon = path!("favicon.ico")
+        .and(logo_options)
+        .and(with_config.clone())
+        .and_then(|options: logo::LogoOptions, config: Arc<Config>| {
+            let options = options.with_format(logo::Format::Ico);
+            poll_fn(move || {
+                blocking(|| logo_route(&config, &options, None, false))
+                    .map_err(warp::reject::custom)
+            })
+        });
+    // GET /logo.mjpeg
+    let logo_mjpeg = path!("logo.mjpeg")
+        .and(logo_options)
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_mjpeg_route(&config, &options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/logo/bounds
+    let logo_bounds = path!("api" / "v1" / "logo" / "bounds")
+        .and(logo_options)
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_bounds_route(&config, &options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/logo/pixels
+    let logo_pixels = path!("api" / "v1" / "logo" / "pixels")
+        .and(logo_options)
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_pixels_route(&config, &options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/logo/dominant
+    let logo_dominant = path!("api" / "v1" / "logo" / "dominant")
+        .and(logo_options)
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_dominant_route(&config, &options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/icons.zip
+    let icons = path!("api" / "v1" / "icons.zip")
+        .and(warp::query::<logo::IconOptions>())
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| icons_route(&config, &options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/logo/delta
+    let logo_delta = path!("api" / "v1" / "logo" / "delta")
+        .and(warp::query::<db::DeltaOptions>())
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_delta_route(&config, options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/logo/transition.json
+    let logo_transition = path!("api" / "v1" / "logo" / "transition.json")
+        .and(warp::query::<db::TransitionOptions>())
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_transition_route(&config, options)).map_err(warp::reject::custom)
+            })
+        });
+    // GET /api/v1/logo/mono
+    let logo_mono = path!("api" / "v1" / "logo" / "mono")
+        .and(logo_options)
+        .and(warp::query::<logo::MonoOptions>())
+        .and(with_config.clone())
+        .and_then(|options, mono_options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| logo_mono_route(&config, &options, mono_options))
+                    .map_err(warp::reject::custom)
+            })
+        });
+    // POST /api/v1/logo/render
+    let logo_render = warp::post2()
+        .and(path!("api" / "v1" / "logo" / "render"))
+        .and(post_body_limit)
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::concat())
+        .and(logo_options)
+        .and(with_config.clone())
+        .and_then(
+            |content_encoding: Option<String>,
+             request_body: warp::body::FullBody,
+             options: logo::LogoOptions,
+             config: Arc<Config>| {
+                let options = options.with_default_format(config.default_format);
+                let budget = Budget::start(config.render_time_budget);
+                let live_logo = body::decode_json(
+                    request_body.bytes(),
+                    content_encoding.as_deref(),
+                    config.max_decompressed_size,
+                );
+                poll_fn(move || {
+                    blocking(|| logo_render_route(&config, &live_logo, &options, budget))
+                        .map_err(warp::reject::custom)
+                })
+            },
+        );
+    // GET /api/v1/capabilities
+    let capabilities = path!("api" / "v1" / "capabilities")
+        .and(with_config.clone())
+        .map(|config: Arc<Config>| reply::json(&capabilities::get_capabilities(&config)));
+    // GET /api/v1/openapi.json
+    let openapi = path!("api" / "v1" / "openapi.json").map(|| {
+        Response::builder()
+            .header("Content-Type", "application/json")
+            .body(include_str!("openapi.json"))
     });
     // GET /
     let index = path::end().and(warp::fs::file("src/index.html"));
     // GET /history
     let history = path!("history").and(warp::fs::file("history-frontend/history.html"));
+    // GET /history.html
+    let history_page = path!("history.html")
+        .and(warp::query::<db::HistoryPageOptions>())
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| db_route(db::get_history_page(&config, options)))
+                    .map_err(warp::reject::custom)
+            })
+        });
     // GET /history/elm.js
     let history_elm = path!("history.js").and(warp::fs::file("history-frontend/history.js"));
     // GET /health
-    let health = path!("health").map(|| "OK");
+    let health = path!("health")
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_config.clone())
+        .map(|accept: Option<String>, config: Arc<Config>| {
+            health_route(&config, accept.as_deref())
+        });
+    // GET /metrics
+    let metrics = path!("metrics").map(|| {
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(metrics::render())
+    });
+    // The websocket routes don't exist when the websocket is disabled
+    let websocket_enabled = with_config
+        .clone()
+        .and_then(|config: Arc<Config>| {
+            if config.enable_websocket {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
     // GET /live (websocket)
     let live = warp::path("live")
+        .and(warp::path::end())
+        .and(websocket_enabled.clone())
+        .and(warp::query::<live::LiveOptions>())
         // The `ws2()` filter will prepare Websocket handshake...
         .and(warp::ws2())
-        .map(|ws: warp::ws::Ws2| {
-            // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| live::listener_connected(socket))
-        });
+        .and(with_config.clone())
+        .and(client_ip::client_ip(config.clone()))
+        .map(
+            |options: live::LiveOptions,
+             ws: warp::ws::Ws2,
+             config: Arc<Config>,
+             ip: Option<IpAddr>| {
+                if let Err(err) = options.validate(&config) {
+                    return bad_request(err);
+                }
+                // This will call our function if the handshake succeeds.
+                Ok(ws
+                    .on_upgrade(move |socket| live::listener_connected(config, ip, options, socket))
+                    .into_response())
+            },
+        );
+    // GET /live/playback (websocket)
+    let live_playback = path!("live" / "playback")
+        .and(websocket_enabled)
+        .and(warp::query::<live::PlaybackOptions>())
+        .and(warp::ws2())
+        .and(with_config.clone())
+        .and(client_ip::client_ip(config.clone()))
+        .map(
+            |options: live::PlaybackOptions,
+             ws: warp::ws::Ws2,
+             config: Arc<Config>,
+             ip: Option<IpAddr>| {
+                if let Err(err) = options.validate() {
+                    return bad_request(err);
+                }
+                let slot = match live::PlaybackSlot::take(config.max_playbacks) {
+                    Some(slot) => slot,
+                    None => {
+                        return Response::builder()
+                            .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                            .header("Retry-After", "60")
+                            .body("Too many playbacks are running, try again later".into())
+                    }
+                };
+                Ok(ws
+                    .on_upgrade(move |socket| {
+                        live::playback_connected(config, ip, options, slot, socket)
+                    })
+                    .into_response())
+            },
+        );
     // GET /api/v1/history
     let history_api = path!("api" / "v1" / "history")
         .and(get_history_options)
-        .and_then(|options| {
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
+            let budget = Budget::start(config.history_time_budget);
             poll_fn(move || {
-                blocking(|| db::get_history(options).expect("Could not get history"))
-                    .map_err(|err| warp::reject::custom(err))
+                blocking(|| db_route(db::get_history(&config, options, budget)))
+                    .map_err(warp::reject::custom)
             })
         });
-    let history_api_by_date =
-        path!("api" / "v1" / "history" / String).and_then(|entry_date: String| {
+    let history_api_sample = path!("api" / "v1" / "history" / "sample")
+        .and(warp::query::<db::SampleHistoryOptions>())
+        .and(with_config.clone())
+        .and_then(|options, config: Arc<Config>| {
             poll_fn(move || {
-                blocking(|| {
-                    db::get_history_from_date(entry_date.clone())
-                        .expect("Could not get history at index")
+                blocking(|| db_route(db::sample_history(&config, options)))
+                    .map_err(warp::reject::custom)
+            })
+        });
+    let history_api_export = path!("api" / "v1" / "history" / "export.ndjson")
+        .and(warp::query::<db::ExportOptions>())
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(with_config.clone())
+        .and_then(
+            |options, accept_encoding: Option<String>, config: Arc<Config>| {
+                let gzip = accept_encoding.is_some_and(|encoding| accepts(&encoding, "gzip"));
+                poll_fn(move || {
+                    blocking(|| db_route(db::export_history(config.clone(), options, gzip)))
+                        .map_err(warp::reject::custom)
+                })
+            },
+        );
+    let history_api_by_date = path!("api" / "v1" / "history" / String)
+        .and(warp::query::<db::HistoryEntryOptions>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(with_config.clone())
+        .and_then(
+            |entry_date: String, options, if_none_match: Option<String>, config: Arc<Config>| {
+                poll_fn(move || {
+                    blocking(|| {
+                        db_route(db::get_history_from_date(
+                            &config,
+                            entry_date.clone(),
+                            options,
+                            if_none_match.clone(),
+                        ))
+                    })
+                    .map_err(warp::reject::custom)
                 })
-                .map_err(|err| warp::reject::custom(err))
+            },
+        );
+    let history_api_first_seen = path!("api" / "v1" / "history" / "first-seen" / String)
+        .and(warp::query::<db::FirstSeenOptions>())
+        .and(with_config.clone())
+        .and_then(|hash: String, options, config: Arc<Config>| {
+            poll_fn(move || {
+                blocking(|| db_route(db::get_first_seen(&config, hash.clone(), options)))
+                    .map_err(warp::reject::custom)
+            })
+        });
+    let history_api_search = path!("api" / "v1" / "history" / "search")
+        .and(warp::query::<db::SearchHistoryOptions>())
+        .and(with_config.clone())
+        .and_then(|options: db::SearchHistoryOptions, config: Arc<Config>| {
+            let budget = Budget::start(config.history_time_budget);
+            poll_fn(move || {
+                blocking(|| db_route(db::search_history(&config, options.clone(), budget)))
+                    .map_err(warp::reject::custom)
             })
         });
-    let history_api_index = path!("api" / "v1" / "history" / "index").and_then(|| {
-        poll_fn(move || {
-            blocking(|| db::get_history_index().expect("Could not get history index"))
-                .map_err(|err| warp::reject::custom(err))
+    let history_api_index = path!("api" / "v1" / "history" / "index")
+        .and(warp::query::<db::GetHistoryIndexOptions>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(with_config.clone())
+        .and_then(
+            |options, if_none_match: Option<String>, config: Arc<Config>| {
+                poll_fn(move || {
+                    blocking(|| {
+                        db_route(db::get_history_index(
+                            &config,
+                            options,
+                            if_none_match.clone(),
+                        ))
+                    })
+                    .map_err(warp::reject::custom)
+                })
+            },
+        );
+
+    // The HTML pages get the security headers
+    let pages = index
+        .or(history)
+        .or(history_page)
+        .and(with_config.clone())
+        .map(|page, config: Arc<Config>| with_security_headers(page, &config));
+
+    // Everything under /api can be called from the cors origin. The cors filter answers the
+    // preflight of any path it gets, so it only gets the api paths.
+    let api_path = warp::path::full()
+        .and_then(|path: path::FullPath| {
+            if path.as_str().starts_with("/api/") {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
         })
-    });
+        .untuple_one();
+    let api = logo_bounds
+        .or(logo_pixels)
+        .or(logo_dominant)
+        .or(icons)
+        .or(logo_delta)
+        .or(logo_transition)
+        .or(logo_mono)
+        .or(logo_render)
+        .or(history_api_index)
+        .or(history_api_search)
+        .or(history_api_first_seen)
+        .or(history_api_export)
+        .or(history_api_sample)
+        .or(history_api_by_date)
+        .or(history_api)
+        .or(capabilities)
+        .or(openapi)
+        .with(cors)
+        .boxed();
+    let allowed_origin = warp::header::optional::<String>("origin")
+        .and(with_config.clone())
+        .and_then(allow_cors_origin)
+        .untuple_one();
+    let api = warp::method()
+        .and(api_path)
+        .and(allowed_origin)
+        .and(api)
+        .map(answer_preflight);
 
-    let routes = index
+    pages
         .or(logo)
+        .or(logo_mjpeg)
+        .or(favicon)
         .or(health)
+        .or(metrics)
         .or(live)
-        .or(history_api_index)
-        .or(history_api_by_date)
-        .or(history_api.with(cors).boxed())
-        .or(history)
-        .or(history_elm);
+        .or(live_playback)
+        .or(api)
+        .or(history_elm)
+}
 
-    let main = routes;
+fn logo_route(
+    config: &Config,
+    options: &logo::LogoOptions,
+    accept: Option<&str>,
+    sized_by_hint: bool,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate(config) {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
 
-    warp::serve(main).run(([0, 0, 0, 0], 3000));
+    let animation = if accept.is_some_and(|accept| accepts(accept, "image/apng")) {
+        logo::get_logo_animation(config, options)
+    } else {
+        Ok(None)
+    };
+    let max_bytes = options.max_bytes();
+    let rendered = animation.and_then(|animation| match animation {
+        // An animation that is too large falls back to a still image that fits
+        Some(apng) if max_bytes.is_none_or(|max_bytes| apng.len() <= max_bytes) => {
+            Ok(Some((apng, "image/apng", None)))
+        }
+        _ => match max_bytes {
+            Some(max_bytes) => {
+                logo::get_logo_image_within_bytes(config, options, max_bytes).map(|fitted| {
+                    fitted.map(|(logo, encoding)| {
+                        (logo, options.format().content_type(), Some(encoding))
+                    })
+                })
+            }
+            None => logo::get_logo_image(config, options)
+                .map(|logo| Some((logo, options.format().content_type(), None))),
+        },
+    });
+    let (logo, content_type, encoding) = match rendered {
+        Ok(Some(rendered)) => rendered,
+        Ok(None) => {
+            return Response::builder()
+                .status(http::StatusCode::PAYLOAD_TOO_LARGE)
+                .body(format!("The logo doesn't fit in {} bytes", max_bytes.unwrap_or(0)).into())
+        }
+        Err(err) => {
+            eprintln!("Error generating {:?}: {}", options.format(), err);
+            return render_error(config);
+        }
+    };
+    let mut response = Response::builder();
+    response.header("Content-Type", content_type);
+    if let Some(encoding) = encoding {
+        // Tells embedders with size limits what the image was shrunk to
+        response.header("X-Logo-Encoding", encoding);
+    }
+    if options.animate() {
+        // The image depends on whether the client accepts APNG
+        response.header("Vary", "Accept");
+    }
+    if sized_by_hint {
+        response
+            .header("Vary", "Sec-CH-DPR, DPR")
+            .header("Content-DPR", options.size().unwrap_or(1));
+    }
+    response.body(logo.into())
+}
 
-    Ok(())
+/// The device pixel ratio from the `Sec-CH-DPR` client hint, or the older `DPR` one
+fn device_pixel_ratio(sec_ch_dpr: Option<&str>, dpr: Option<&str>) -> Option<f64> {
+    sec_ch_dpr
+        .or(dpr)
+        .and_then(|dpr| dpr.trim().parse::<f64>().ok())
+        .filter(|dpr| dpr.is_finite() && *dpr > 0.0)
+}
+
+/// Answers a request for an image that could not be rendered with the configured error image
+fn render_error(config: &Config) -> Result<reply::Response, http::Error> {
+    let mut response = Response::builder();
+    response.status(config.error_status);
+    if !config.error_image_data.is_empty() {
+        response.header("Content-Type", "image/png");
+    }
+    response.body(config.error_image_data.to_vec().into())
+}
+
+/// The health check with the load of the websocket, for clients that accept json
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(flatten)]
+    load: live::Load,
+}
+
+fn health_route(config: &Config, accept: Option<&str>) -> Result<reply::Response, http::Error> {
+    let (status, message, health) = if logo::poller_stalled(config) {
+        (
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            "The poller has stalled, so the logo may be stale",
+            "stalled",
+        )
+    } else {
+        (http::StatusCode::OK, "OK", "ok")
+    };
+
+    if accept.is_some_and(|accept| accepts(accept, "application/json")) {
+        let mut response = reply::json(&Health {
+            status: health,
+            load: live::load(),
+        })
+        .into_response();
+        *response.status_mut() = status;
+        Ok(response)
+    } else {
+        Response::builder().status(status).body(message.into())
+    }
+}
+
+/// Whether an `Accept` or `Accept-Encoding` header lists a media type or encoding
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|accepted| {
+        accepted
+            .split(';')
+            .next()
+            .is_some_and(|accepted| accepted.trim().eq_ignore_ascii_case(media_type))
+    })
+}
+
+fn logo_mjpeg_route(
+    config: &Config,
+    options: &logo::LogoOptions,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate(config) {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
+
+    match mjpeg::connect(config, options.clone()) {
+        Ok(body) => Response::builder()
+            .header(
+                "Content-Type",
+                format!("multipart/x-mixed-replace; boundary={}", mjpeg::BOUNDARY),
+            )
+            .header("Cache-Control", "no-cache")
+            .body(body),
+        Err(mjpeg::Error::Full) => Response::builder()
+            .status(http::StatusCode::SERVICE_UNAVAILABLE)
+            .body("Too many clients are streaming the logo".into()),
+        Err(mjpeg::Error::Render(err)) => {
+            eprintln!("Error generating MJPEG frame: {}", err);
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not render logo".into())
+        }
+    }
+}
+
+fn logo_bounds_route(
+    config: &Config,
+    options: &logo::LogoOptions,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate(config) {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
+
+    match logo::get_logo_bounds(config, options) {
+        Ok(bounds) => Ok(reply::json(&bounds).into_response()),
+        Err(err) => {
+            eprintln!("Error finding logo bounds: {}", err);
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not render logo".into())
+        }
+    }
 }
 
-fn logo_route(options: logo::LogoOptions) -> Result<reply::Response, http::Error> {
-    let logo_png = match logo::get_logo_png(options) {
-        Ok(logo) => logo,
+fn logo_pixels_route(
+    config: &Config,
+    options: &logo::LogoOptions,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate(config) {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
+
+    match logo::get_logo_pixels(config, options) {
+        Ok(pixels) => Ok(reply::json(&pixels).into_response()),
         Err(err) => {
-            eprintln!("Error generating png: {}", err);
-            include_bytes!("error.png").to_vec()
+            eprintln!("Error finding logo pixels: {}", err);
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not render logo".into())
+        }
+    }
+}
+
+fn logo_dominant_route(
+    config: &Config,
+    options: &logo::LogoOptions,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate(config) {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
+
+    match logo::get_logo_dominant(config, options) {
+        Ok(dominant) => Ok(reply::json(&dominant).into_response()),
+        Err(err) => {
+            eprintln!("Error finding the dominant color: {}", err);
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not render logo".into())
+        }
+    }
+}
+
+fn icons_route(
+    config: &Config,
+    options: &logo::IconOptions,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate() {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
+
+    match logo::get_icon_set(config, options) {
+        Ok(zip) => Response::builder()
+            .header("Content-Type", "application/zip")
+            .header("Content-Disposition", "attachment; filename=\"icons.zip\"")
+            .body(zip.into()),
+        Err(err) => {
+            eprintln!("Error generating icons: {}", err);
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not render logo".into())
+        }
+    }
+}
+
+fn logo_delta_route(config: &Config, options: db::DeltaOptions) -> reply::Response {
+    if !logo::is_ready() {
+        return not_ready(config).unwrap_or_default();
+    }
+    db_route(db::get_logo_delta(config, options))
+}
+
+fn logo_transition_route(config: &Config, options: db::TransitionOptions) -> reply::Response {
+    if !config.enable_transitions {
+        return Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body("Transitions are turned off".into())
+            .unwrap_or_default();
+    }
+    if !logo::is_ready() {
+        return not_ready(config).unwrap_or_default();
+    }
+    db_route(db::get_logo_transition(config, options))
+}
+
+fn logo_mono_route(
+    config: &Config,
+    options: &logo::LogoOptions,
+    mono_options: logo::MonoOptions,
+) -> Result<reply::Response, http::Error> {
+    if let Err(err) = options.validate(config) {
+        return bad_request(err);
+    }
+    if !logo::is_ready() {
+        return not_ready(config);
+    }
+
+    match logo::get_logo_mono(config, options, mono_options) {
+        Ok(bitmap) => Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .header("X-Width", bitmap.width)
+            .header("X-Height", bitmap.height)
+            .header("X-Row-Stride", bitmap.stride)
+            .body(bitmap.data.into()),
+        Err(err) => {
+            eprintln!("Error generating mono logo: {}", err);
+            Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Could not render logo".into())
+        }
+    }
+}
+
+/// Tells the client to come back once the first poll of the upstream should be done
+fn not_ready(config: &Config) -> Result<reply::Response, http::Error> {
+    Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", config.poll_interval.max(1))
+        .body("The logo has not been fetched yet".into())
+}
+
+fn logo_render_route(
+    config: &Config,
+    live_logo: &Result<logo::LogoResponse, body::Error>,
+    options: &logo::LogoOptions,
+    budget: Budget,
+) -> Result<reply::Response, http::Error> {
+    let live_logo = match live_logo {
+        Ok(live_logo) => live_logo,
+        Err(err) => {
+            return Response::builder()
+                .status(err.status())
+                .body(err.to_string().into())
         }
     };
-    Ok(Response::builder().body(logo_png.into())?)
+    if let Err(err) = options.validate(config).and_then(|()| live_logo.validate()) {
+        return bad_request(err);
+    }
+
+    match logo::render_image_within(config, live_logo, options, budget) {
+        Ok(image) => Response::builder()
+            .header("Content-Type", options.format().content_type())
+            .body(image.into()),
+        Err(ref err) if err.is::<budget::Exceeded>() => over_budget(),
+        // The logo comes from the client, so anything that stops it from rendering is their error
+        Err(err) => bad_request(format!("Could not render the logo: {}", err)),
+    }
+}
+
+/// Only lets requests from the current cors origin (or without one) through to the cors filter, so
+/// a reload changes the origin
+#[allow(deprecated)]
+fn allow_cors_origin(origin: Option<String>, config: Arc<Config>) -> Result<(), Rejection> {
+    match origin {
+        Some(ref origin) if *origin != config.cors_origin => Err(warp::reject::forbidden()),
+        _ => Ok(()),
+    }
+}
+
+/// Gives a cors preflight the status of a response without content
+fn answer_preflight(method: http::Method, reply: impl Reply) -> reply::Response {
+    let mut response = reply.into_response();
+    if method == http::Method::OPTIONS && response.status() == http::StatusCode::OK {
+        *response.status_mut() = http::StatusCode::NO_CONTENT;
+    }
+    response
+}
+
+/// Adds the configured security headers to an HTML page
+fn with_security_headers(page: impl Reply, config: &Config) -> reply::Response {
+    let mut response = page.into_response();
+    let headers = [
+        ("content-security-policy", &config.content_security_policy),
+        ("x-frame-options", &config.frame_options),
+        ("referrer-policy", &config.referrer_policy),
+    ];
+    for (name, value) in &headers {
+        // The values are checked when the config is loaded
+        if let Ok(value) = http::HeaderValue::from_str(value) {
+            if !value.is_empty() {
+                response.headers_mut().insert(*name, value);
+            }
+        }
+    }
+    response
+}
+
+fn bad_request(message: String) -> Result<reply::Response, http::Error> {
+    Response::builder()
+        .status(http::StatusCode::BAD_REQUEST)
+        .body(message.into())
+}
+
+/// Tells the client that the request was abandoned, as it took longer than its time budget
+fn over_budget() -> Result<reply::Response, http::Error> {
+    Response::builder()
+        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+        .body("The request took longer than its time budget".into())
+}
+
+fn db_route(result: Result<reply::Response, db::Error>) -> reply::Response {
+    result.unwrap_or_else(|err| {
+        if err.is_over_budget() {
+            return over_budget().unwrap_or_default();
+        }
+        if err.is_bad_request() {
+            return bad_request(err.to_string()).unwrap_or_default();
+        }
+        eprintln!("Database error: {}", err);
+        let (status, body) = if err.is_timeout() {
+            (
+                http::StatusCode::GATEWAY_TIMEOUT,
+                "Database query timed out",
+            )
+        } else {
+            (http::StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        };
+        let mut response = reply::Response::new(body.into());
+        *response.status_mut() = status;
+        response
+    })
 }
 
 // This function receives a `Rejection` and tries to return a custom
@@ -116,3 +982,237 @@ fn logo_route(options: logo::LogoOptions) -> Result<reply::Response, http::Error
 // fn customize_error(err: Rejection) -> Result<String, http::Error> {
 //     Ok(err.to_string())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documented_paths_exist() {
+        let routes = routes(Arc::new(ConfigHandle::new(Config::default())));
+        let spec: serde_json::Value = serde_json::from_str(include_str!("openapi.json")).unwrap();
+
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            let path = path
+                .replace("{date}", "2019-09-01T12:00:00Z")
+                .replace("{hash}", &"0".repeat(64));
+            for method in operations.as_object().unwrap().keys() {
+                let mut request = warp::test::request()
+                    .method(&method.to_uppercase())
+                    .path(&path);
+                if method == "post" {
+                    request = request.json(&serde_json::json!({ "logo": [] }));
+                }
+                let result = request.filter(&routes);
+                if let Err(rejection) = result {
+                    assert!(
+                        rejection.status() != http::StatusCode::NOT_FOUND
+                            && rejection.status() != http::StatusCode::METHOD_NOT_ALLOWED,
+                        "documented {} {} is not routed",
+                        method,
+                        path
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_images_larger_than_max_image_size() {
+        let config = Config::default();
+        let routes = routes(Arc::new(ConfigHandle::new(config)));
+
+        // The logo is 152 pixels wide at size 1, 4 times that when smooth, and a shadow adds 3 times
+        // its blur
+        for query in &[
+            "size=100000",
+            "size=4&smooth=true",
+            "size=13&shadow=32:000000ff",
+        ] {
+            let response = warp::test::request()
+                .path(&format!("/logo.png?{}", query))
+                .reply(&routes);
+            assert_eq!(
+                response.status(),
+                http::StatusCode::BAD_REQUEST,
+                "{}",
+                query
+            );
+        }
+        let response = warp::test::request()
+            .path("/live?sizes=1,100000")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .reply(&routes);
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_images_without_pixels() {
+        let routes = routes(Arc::new(ConfigHandle::new(Config::default())));
+
+        for path in &[
+            "/logo.png?size=0",
+            "/logo.png?character=1&size=0",
+            "/api/v1/logo/pixels?size=0",
+        ] {
+            let response = warp::test::request().path(path).reply(&routes);
+            assert_eq!(response.status(), http::StatusCode::BAD_REQUEST, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_icons_larger_than_256_pixels() {
+        let routes = routes(Arc::new(ConfigHandle::new(Config::default())));
+        let status = |query| {
+            warp::test::request()
+                .path(&format!("/logo.png?format=ico&{}", query))
+                .reply(&routes)
+                .status()
+        };
+
+        assert_eq!(status("size=2"), http::StatusCode::BAD_REQUEST);
+        assert_eq!(status("tile=300x16"), http::StatusCode::BAD_REQUEST);
+        // Only the size of the icon counts, not what it is rendered from
+        assert_ne!(status("size=1&smooth=true"), http::StatusCode::BAD_REQUEST);
+        assert_ne!(status("size=8&character=1"), http::StatusCode::BAD_REQUEST);
+        assert_ne!(status("size=4&avatar=64"), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_history_times_that_are_not_timestamps() {
+        let routes = routes(Arc::new(ConfigHandle::new(Config::default())));
+
+        for path in &["/api/v1/history/not-a-date", "/api/v1/history/2019-01-01"] {
+            let response = warp::test::request().path(path).reply(&routes);
+            assert_eq!(response.status(), http::StatusCode::BAD_REQUEST, "{}", path);
+        }
+    }
+
+    #[test]
+    fn bounds_the_playbacks_that_run_at_once() {
+        let config = Config {
+            max_playbacks: 0,
+            ..Config::default()
+        };
+        let routes = routes(Arc::new(ConfigHandle::new(config)));
+
+        let response = warp::test::request()
+            .path("/live/playback?speed=10")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .reply(&routes);
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "60");
+    }
+
+    #[test]
+    fn limits_the_size_of_post_bodies() {
+        let config = Config {
+            max_body_size: 16,
+            ..Config::default()
+        };
+        let routes = routes(Arc::new(ConfigHandle::new(config)));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/logo/render")
+            .header("content-length", "17")
+            .body([b' '; 17])
+            .reply(&routes);
+        assert_eq!(response.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a length, the size isn't known until the body has been read
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/logo/render")
+            .body([b' '; 8])
+            .reply(&routes);
+        assert_eq!(response.status(), http::StatusCode::LENGTH_REQUIRED);
+    }
+
+    #[test]
+    fn answers_preflights_for_the_api() {
+        let config = Config::default();
+        let routes = routes(Arc::new(ConfigHandle::new(config.clone())));
+
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path("/api/v1/logo/render")
+            .header("origin", config.cors_origin.as_str())
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type")
+            .reply(&routes);
+
+        assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            config.cors_origin.as_str()
+        );
+        let allowed = |name| headers[name].to_str().unwrap().to_lowercase();
+        assert!(allowed("access-control-allow-methods").contains("post"));
+        assert!(allowed("access-control-allow-headers").contains("content-type"));
+
+        // Other origins and paths outside the api are not allowed
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path("/api/v1/logo/render")
+            .header("origin", "http://example.com")
+            .header("access-control-request-method", "POST")
+            .reply(&routes);
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path("/logo.png")
+            .header("origin", config.cors_origin.as_str())
+            .header("access-control-request-method", "GET")
+            .reply(&routes);
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn follows_the_cors_origin_after_a_reload() {
+        let config_handle = Arc::new(ConfigHandle::new(Config::default()));
+        let routes = routes(config_handle.clone());
+        let preflight = |origin| {
+            warp::test::request()
+                .method("OPTIONS")
+                .path("/api/v1/capabilities")
+                .header("origin", origin)
+                .header("access-control-request-method", "GET")
+                .reply(&routes)
+                .status()
+        };
+        assert_eq!(preflight("http://example.com"), http::StatusCode::FORBIDDEN);
+
+        config_handle.replace(Config {
+            cors_origin: "http://example.com".to_owned(),
+            ..Config::default()
+        });
+
+        assert_eq!(
+            preflight("http://example.com"),
+            http::StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            preflight("http://localhost:8000"),
+            http::StatusCode::FORBIDDEN
+        );
+        let response = warp::test::request()
+            .path("/api/v1/capabilities")
+            .header("origin", "http://example.com")
+            .reply(&routes);
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "http://example.com"
+        );
+    }
+}
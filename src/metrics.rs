@@ -0,0 +1,113 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the buckets of every histogram, in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// A latency histogram in the Prometheus text format, which only takes atomic adds to update
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    /// Observations in each bucket alone, which are added up when written
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Histogram {
+            name,
+            help,
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, observing how long it took
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.observe(start.elapsed());
+        result
+    }
+
+    fn write(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, cumulative
+            );
+        }
+        // Read last, so it is never less than the buckets it includes
+        let count = self.count.load(Ordering::Relaxed).max(cumulative);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", self.name, sum);
+        let _ = writeln!(out, "{}_count {}", self.name, count);
+    }
+}
+
+pub static PNG_ENCODE: Histogram = Histogram::new(
+    "logo_png_encode_seconds",
+    "Time taken to encode a PNG of the logo",
+);
+pub static HISTORY_QUERY: Histogram = Histogram::new(
+    "logo_png_history_query_seconds",
+    "Time taken by the database queries of the history",
+);
+pub static UPSTREAM_FETCH: Histogram = Histogram::new(
+    "logo_png_upstream_fetch_seconds",
+    "Time taken to fetch the logo from the upstream",
+);
+
+/// Every metric, in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    for histogram in &[&PNG_ENCODE, &HISTORY_QUERY, &UPSTREAM_FETCH] {
+        histogram.write(&mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_cumulative_buckets() {
+        let histogram = Histogram::new("test_seconds", "Test");
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.write(&mut out);
+        assert!(out.contains("test_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 60.0205\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, Stream};
+use futures::sync::mpsc;
+use hyper::Body;
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use tokio::timer::Interval;
+
+use crate::config::Config;
+use crate::logo::{self, Format, LogoOptions};
+
+/// Separates the frames of the stream
+pub const BOUNDARY: &str = "frame";
+
+/// Longest a client goes without a frame. Sending the current one again while the logo doesn't
+/// change finds clients that have disconnected, which the server only notices when it writes.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A client of `/logo.mjpeg`, which gets the logo rendered with its own options
+struct Client {
+    options: LogoOptions,
+    // Holds a single frame, as a client that is behind only needs the latest one
+    tx: mpsc::Sender<Vec<u8>>,
+    // The frame it was last sent, to send again while the logo doesn't change
+    last_frame: Arc<Mutex<Vec<u8>>>,
+}
+
+// Next id for use by an MJPEG client
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+lazy_static! {
+    static ref CLIENTS: RwLock<HashMap<usize, Client>> = RwLock::new(HashMap::new());
+}
+
+/// Why a client could not be connected
+pub enum Error {
+    /// There are `max_mjpeg_clients` clients already
+    Full,
+    Render(Box<dyn std::error::Error>),
+}
+
+/// Sends the live logo to every client, rendered the way each of them asked for
+pub fn send_update(config: &Config) {
+    CLIENTS.write().retain(|id, client| {
+        let frame = match logo::get_logo_image(config, &client.options) {
+            Ok(frame) => frame,
+            Err(err) => {
+                eprintln!("Error rendering frame for MJPEG client {}: {}", id, err);
+                return true;
+            }
+        };
+        *client.last_frame.lock() = frame.clone();
+        match client.tx.try_send(frame) {
+            Ok(()) => true,
+            // A client that is still sending the last frame skips this one, and gets the next
+            Err(err) => !err.is_disconnected(),
+        }
+    });
+}
+
+/// Connects a client, returning the body of the stream, which starts with the live logo
+pub fn connect(config: &Config, options: LogoOptions) -> Result<Body, Error> {
+    let options = options.with_format(Format::Jpeg);
+    let mut clients = CLIENTS.write();
+    // Clients that have disconnected are otherwise only found on the next update
+    clients.retain(|_, client| !client.tx.is_closed());
+    if clients.len() >= config.max_mjpeg_clients {
+        return Err(Error::Full);
+    }
+
+    let first_frame = logo::get_logo_image(config, &options).map_err(Error::Render)?;
+    let last_frame = Arc::new(Mutex::new(first_frame.clone()));
+    let (tx, rx) = mpsc::channel(0);
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    clients.insert(
+        id,
+        Client {
+            options,
+            tx,
+            last_frame: last_frame.clone(),
+        },
+    );
+
+    // The updates end when the client is removed, and with them the stream
+    let updates = stream::once(Ok(first_frame))
+        .chain(rx)
+        .map(Some)
+        .chain(stream::once(Ok(None)));
+    // Also often enough that the connection doesn't count as idle
+    let mut repeat = REPEAT_INTERVAL;
+    if config.idle_timeout > 0 {
+        repeat = repeat.min(Duration::from_secs(config.idle_timeout) / 2);
+    }
+    let repeats = Interval::new(Instant::now() + repeat, repeat)
+        .map(move |_| Some(last_frame.lock().clone()))
+        .map_err(|err| eprintln!("MJPEG timer error: {}", err));
+    let frames = updates.select(repeats);
+
+    let parts = frames
+        .take_while(|frame| Ok(frame.is_some()))
+        .filter_map(|frame| frame)
+        .map(|frame| {
+            let mut part = format!(
+                "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                frame.len()
+            )
+            .into_bytes();
+            part.extend(frame);
+            part.extend_from_slice(b"\r\n");
+            part
+        })
+        .map_err(|()| io::Error::other("MJPEG stream failed"));
+    Ok(Body::wrap_stream(parts))
+}
@@ -0,0 +1,135 @@
+use std::error::Error;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::RwLock;
+use reqwest::{header, StatusCode};
+
+use crate::config::{Config, PollStrategy};
+use crate::logo::LogoResponse;
+
+/// Somewhere to get the live logo from
+pub trait Fetcher {
+    /// Fetches the live logo, or `None` if it is known not to have changed since the last fetch
+    fn fetch(&self) -> Result<Option<LogoResponse>, Box<dyn Error>>;
+}
+
+/// The fetcher the config asks for: the replay file if there is one, or else the upstream
+pub fn from_config(config: &Config) -> Result<Box<dyn Fetcher + Send + Sync>, Box<dyn Error>> {
+    if config.replay_file.is_empty() {
+        Ok(Box::new(HttpFetcher::new(config)))
+    } else {
+        Ok(Box::new(ReplayFetcher::open(&config.replay_file)?))
+    }
+}
+
+/// Fetches the live logo from the logo-api
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    url: String,
+    poll_strategy: PollStrategy,
+    auth: Option<(header::HeaderName, header::HeaderValue)>,
+    // Validators the api sent with the last logo, for conditional polling
+    validators: RwLock<Validators>,
+}
+
+#[derive(Debug, Default)]
+struct Validators {
+    etag: Option<header::HeaderValue>,
+    last_modified: Option<header::HeaderValue>,
+}
+
+impl HttpFetcher {
+    pub fn new(config: &Config) -> Self {
+        // The header is checked when the config is loaded
+        let auth = if config.upstream_auth_header.is_empty() {
+            None
+        } else {
+            let name = header::HeaderName::from_bytes(config.upstream_auth_header.as_bytes());
+            let value = header::HeaderValue::from_str(&config.upstream_auth_value);
+            name.ok().zip(value.ok()).map(|(name, mut value)| {
+                value.set_sensitive(true);
+                (name, value)
+            })
+        };
+
+        HttpFetcher {
+            client: reqwest::Client::new(),
+            url: config.upstream_url.clone(),
+            poll_strategy: config.poll_strategy,
+            auth,
+            validators: RwLock::new(Validators::default()),
+        }
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn fetch(&self) -> Result<Option<LogoResponse>, Box<dyn Error>> {
+        let mut request = self.client.get(&self.url);
+        if let Some((ref name, ref value)) = self.auth {
+            request = request.header(name.clone(), value.clone());
+        }
+        if self.poll_strategy == PollStrategy::Conditional {
+            let validators = self.validators.read();
+            if let Some(ref etag) = validators.etag {
+                request = request.header(header::IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(ref last_modified) = validators.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+
+        let mut response = request.send()?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        let logo = response.json()?;
+        // Only keep the validators of a logo that could be read, or it would never be fetched again
+        *self.validators.write() = Validators {
+            etag: response.headers().get(header::ETAG).cloned(),
+            last_modified: response.headers().get(header::LAST_MODIFIED).cloned(),
+        };
+
+        Ok(Some(logo))
+    }
+}
+
+/// Replays logos captured from the upstream, to reproduce a sequence of logos without it. Each
+/// fetch gives the next logo in the file, starting over after the last one.
+pub struct ReplayFetcher {
+    logos: Vec<LogoResponse>,
+    next: AtomicUsize,
+}
+
+impl ReplayFetcher {
+    /// Reads a file with a logo as json on each line, skipping blank lines
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let capture =
+            fs::read_to_string(path).map_err(|err| format!("Error reading {}: {}", path, err))?;
+        let logos = capture
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line)
+                    .map_err(|err| format!("Error reading line {} of {}: {}", index + 1, path, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if logos.is_empty() {
+            return Err(format!("{} has no logos to replay", path).into());
+        }
+
+        Ok(ReplayFetcher {
+            logos,
+            next: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Fetcher for ReplayFetcher {
+    fn fetch(&self) -> Result<Option<LogoResponse>, Box<dyn Error>> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(self.logos[next % self.logos.len()].clone()))
+    }
+}
@@ -0,0 +1,101 @@
+use flate2::Crc;
+
+/// Version 2.0 of the format, the first with folders and the oldest that every reader supports
+const VERSION: u16 = 20;
+/// 1980-01-01 in MS-DOS format, the earliest time a zip file can have
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// Writes the files as a zip archive, storing them uncompressed. The files are expected to be
+/// compressed already, like PNGs, or small.
+pub fn write(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = vec![];
+    let mut central_directory = vec![];
+
+    for &(name, data) in files {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let offset = zip.len() as u32;
+
+        // Fields shared by the local header and the central directory entry, from the version
+        // needed to extract up to the length of the extra field
+        let mut fields = vec![];
+        fields.extend_from_slice(&VERSION.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Flags
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Stored, not compressed
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Time
+        fields.extend_from_slice(&DOS_DATE.to_le_bytes());
+        fields.extend_from_slice(&crc.sum().to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes()); // Compressed size
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes()); // Uncompressed size
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Extra field length
+
+        zip.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&fields);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(data);
+
+        central_directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central_directory.extend_from_slice(&VERSION.to_le_bytes()); // Version made by
+        central_directory.extend_from_slice(&fields);
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // Disk number
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // Internal attributes
+        central_directory.extend_from_slice(&0u32.to_le_bytes()); // External attributes
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+    }
+
+    let central_directory_offset = zip.len() as u32;
+    zip.extend_from_slice(&central_directory);
+
+    // End of central directory record
+    zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    zip.extend_from_slice(&0u16.to_le_bytes()); // Number of this disk
+    zip.extend_from_slice(&0u16.to_le_bytes()); // Disk where the central directory starts
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes()); // Entries on this disk
+    zip.extend_from_slice(&(files.len() as u16).to_le_bytes()); // Entries in total
+    zip.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&central_directory_offset.to_le_bytes());
+    zip.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+
+    zip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn stores_files() {
+        let zip = write(&[("a.txt", b"hello"), ("b.txt", b"")]);
+
+        // The first local header, followed by its name and data
+        assert_eq!(read_u32(&zip, 0), 0x0403_4b50);
+        assert_eq!(read_u32(&zip, 14), 0x3610_a686); // CRC-32 of "hello"
+        assert_eq!(read_u32(&zip, 18), 5);
+        assert_eq!(&zip[30..35], b"a.txt");
+        assert_eq!(&zip[35..40], b"hello");
+        assert_eq!(read_u32(&zip, 40), 0x0403_4b50);
+
+        let end = zip.len() - 22;
+        assert_eq!(read_u32(&zip, end), 0x0605_4b50);
+        assert_eq!(read_u16(&zip, end + 10), 2);
+        let central_directory = read_u32(&zip, end + 16) as usize;
+        assert_eq!(read_u32(&zip, central_directory), 0x0201_4b50);
+        // The second entry points at the second local header
+        let second = central_directory + 46 + 5;
+        assert_eq!(&zip[second + 46..second + 51], b"b.txt");
+        assert_eq!(read_u32(&zip, second + 42), 40);
+    }
+}